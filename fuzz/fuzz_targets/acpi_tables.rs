@@ -0,0 +1,27 @@
+//! acpi_tables.rs
+//! Parses arbitrary bytes as the firmware's ACPI tables (see `src/acpi.rs`), &
+//! as the AML we look for `\_S5` in (see `src/power.rs`).
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use thompson_rust_os_parse::acpi::{Fadt, Hpet, Madt, RootTable, Rsdp, Sdt};
+use thompson_rust_os_parse::aml;
+
+fuzz_target!(|data: &[u8]| {
+    Rsdp::parse(data);
+    aml::s5_sleep_types(data);
+    let Some(table) = Sdt::parse(data) else {
+        return;
+    };
+    if let Some(madt) = Madt::parse(&table) {
+        for _ in madt.entries() {}
+        madt.local_apic_address();
+        madt.isa_irq_to_gsi(0);
+    }
+    Fadt::parse(&table);
+    Hpet::parse(&table);
+    if let Some(root) = RootTable::parse(&table) {
+        for _ in root.iter() {}
+    }
+});
@@ -0,0 +1,25 @@
+//! gdb_packet.rs
+//! Throws arbitrary bytes from the serial port at the GDB stub's packet
+//! decoder & the parsers for what's inside packets.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use thompson_rust_os_parse::gdb::{decode_le, parse_addr_len, parse_hex, Decoded, PacketDecoder};
+
+fuzz_target!(|data: &[u8]| {
+    // Same size as the kernel's buffer (`MAX_PACKET_SIZE` in src/gdb.rs).
+    let mut buf = [0u8; 1024];
+    let mut decoder = PacketDecoder::new();
+    for &byte in data {
+        if let Some(Decoded::Packet(len)) = decoder.push(byte, &mut buf) {
+            let packet = &buf[..len];
+            // Roughly what `handle_packet` does with e.g. `m<addr>,<len>`.
+            if let Some((_, args)) = packet.split_first() {
+                parse_addr_len(args);
+                parse_hex(args);
+                decode_le(args);
+            }
+        }
+    }
+});
@@ -0,0 +1,23 @@
+//! symbol_table.rs
+//! Parses arbitrary bytes as the kernel's symbol table (see `tools/ksyms.rs`)
+//! & looks up addresses in whatever comes out.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use thompson_rust_os_parse::ksyms::SymbolTable;
+
+fuzz_target!(|input: (Vec<u64>, &[u8])| {
+    let (addresses, bytes) = input;
+    let Some(table) = SymbolTable::parse(bytes) else {
+        return;
+    };
+    for addr in addresses {
+        if let Some((symbol, offset)) = table.lookup(addr) {
+            assert_eq!(symbol.address + offset, addr);
+            assert!(symbol.size == 0 || offset < symbol.size as u64);
+            // Runs the demangler over the name.
+            let _ = symbol.to_string();
+        }
+    }
+});
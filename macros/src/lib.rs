@@ -0,0 +1,126 @@
+//! lib.rs
+//! Proc macros for `thompson_rust_os`'s test runner. These live in their own crate b/c a
+//! proc-macro crate can't export anything but proc macros (and is compiled for
+//! the host, not our kernel target).
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, ItemFn, LitInt};
+
+/// A `#[test_case]` with options, for the kernel's test runner:
+/// - `should_panic`: the test passes only if it panics
+/// - `ignore`: the test is reported, but not run
+/// - `timeout = N`: allow `N` timer ticks instead of the default
+///
+/// ```ignore
+/// #[kernel_test(should_panic, timeout = 60)]
+/// fn test_divide_by_zero_panics() { ... }
+/// ```
+///
+/// Expands to a `#[test_case] static` (named after the function) holding a
+/// `thompson_rust_os::KernelTest`, with the function itself tucked inside.
+#[proc_macro_attribute]
+pub fn kernel_test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut should_panic = false;
+    let mut ignore = false;
+    let mut timeout = quote!(None);
+    let options = syn::meta::parser(|meta| {
+        if meta.path.is_ident("should_panic") {
+            should_panic = true;
+            Ok(())
+        } else if meta.path.is_ident("ignore") {
+            ignore = true;
+            Ok(())
+        } else if meta.path.is_ident("timeout") {
+            let ticks: LitInt = meta.value()?.parse()?;
+            ticks.base10_parse::<u64>()?;
+            timeout = quote!(Some(#ticks));
+            Ok(())
+        } else {
+            Err(meta.error("expected `should_panic`, `ignore` or `timeout = <ticks>`"))
+        }
+    });
+    parse_macro_input!(attr with options);
+
+    let test = parse_macro_input!(item as ItemFn);
+    let signature = &test.sig;
+    if !signature.inputs.is_empty()
+        || !signature.generics.params.is_empty()
+        || signature.asyncness.is_some()
+    {
+        return syn::Error::new(
+            signature.span(),
+            "kernel tests can't be async, generic or take arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let name = &signature.ident;
+    quote! {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static #name: ::thompson_rust_os::KernelTest = ::thompson_rust_os::KernelTest {
+            name: concat!(module_path!(), "::", stringify!(#name)),
+            test: {
+                #test
+                #name
+            },
+            should_panic: #should_panic,
+            ignore: #ignore,
+            timeout_ticks: #timeout,
+        };
+    }
+    .into()
+}
+
+/// A benchmark for the kernel's test runner, taking a `&mut Bencher`:
+///
+/// ```ignore
+/// #[kernel_bench]
+/// fn bench_new_line(b: &mut Bencher) {
+///     b.iter(|| WRITER.lock().new_line());
+/// }
+/// ```
+///
+/// Expands to a `#[test_case] static` holding a `thompson_rust_os::bench::KernelBench`,
+/// the same way as `#[kernel_test]`.
+#[proc_macro_attribute]
+pub fn kernel_bench(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            proc_macro2::TokenStream::from(attr).span(),
+            "kernel_bench takes no options",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let bench = parse_macro_input!(item as ItemFn);
+    let signature = &bench.sig;
+    if signature.inputs.len() != 1
+        || !signature.generics.params.is_empty()
+        || signature.asyncness.is_some()
+    {
+        return syn::Error::new(
+            signature.span(),
+            "kernel benchmarks take a `&mut Bencher`, & can't be async or generic",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let name = &signature.ident;
+    quote! {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static #name: ::thompson_rust_os::bench::KernelBench =
+            ::thompson_rust_os::bench::KernelBench {
+                name: concat!(module_path!(), "::", stringify!(#name)),
+                bench: {
+                    #bench
+                    #name
+                },
+            };
+    }
+    .into()
+}
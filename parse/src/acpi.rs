@@ -0,0 +1,595 @@
+//! acpi.rs
+//! The [ACPI](https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html)
+//! tables the firmware leaves in memory to describe the machine: the RSDP points
+//! at a root table (RSDT, or XSDT on ACPI 2.0+), which lists the physical
+//! addresses of all the others. Every table after the RSDP starts with the same
+//! 36 byte header, and all of their bytes must add up to 0 (mod 256).
+//!
+//! Finding the tables in physical memory is the kernel's job (`src/acpi.rs`);
+//! these just make sense of the bytes. All fields are little endian, and field
+//! offsets below are from the start of the table, like in the spec.
+
+use crate::bytes::{read_u16, read_u32, read_u64, read_u8};
+
+/// Sum of `bytes`, mod 256. Valid tables sum to 0.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum: u8, &b| sum.wrapping_add(b))
+}
+
+/// Root System Description Pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    /// 0 for ACPI 1.0, 2 for 2.0 and up.
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdt_address: u32,
+    /// Only from ACPI 2.0 on; use this instead of the RSDT when it's there.
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    pub const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    /// Length of an ACPI 1.0 RSDP, which its checksum covers.
+    pub const V1_SIZE: usize = 20;
+    /// Length of an ACPI 2.0+ RSDP.
+    pub const V2_SIZE: usize = 36;
+    /// The RSDP sits on a 16 byte boundary.
+    pub const ALIGN: usize = 16;
+
+    /// Check for a valid RSDP at the start of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Option<Rsdp> {
+        let v1 = bytes.get(..Self::V1_SIZE)?;
+        if &v1[..8] != Self::SIGNATURE || checksum(v1) != 0 {
+            return None;
+        }
+        let revision = v1[15];
+        let mut xsdt_address = None;
+        if revision >= 2 {
+            // The extended checksum covers the whole (longer) structure.
+            let length = read_u32(bytes, 20)? as usize;
+            let v2 = bytes.get(..length.max(Self::V2_SIZE))?;
+            if checksum(v2) != 0 {
+                return None;
+            }
+            xsdt_address = Some(read_u64(bytes, 24)?).filter(|&addr| addr != 0);
+        }
+        Some(Rsdp {
+            revision,
+            oem_id: v1[9..15].try_into().ok()?,
+            rsdt_address: read_u32(v1, 16)?,
+            xsdt_address,
+        })
+    }
+}
+
+/// The header every System Description Table starts with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    /// Of the whole table, header included.
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+}
+
+impl SdtHeader {
+    pub const SIZE: usize = 36;
+
+    pub fn parse(bytes: &[u8]) -> Option<SdtHeader> {
+        let header = bytes.get(..Self::SIZE)?;
+        Some(SdtHeader {
+            signature: header[..4].try_into().ok()?,
+            length: read_u32(header, 4)?,
+            revision: header[8],
+            oem_id: header[10..16].try_into().ok()?,
+            oem_table_id: header[16..24].try_into().ok()?,
+            oem_revision: read_u32(header, 24)?,
+        })
+    }
+}
+
+/// A whole table whose length & checksum checked out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sdt<'a> {
+    pub header: SdtHeader,
+    /// All `header.length` bytes of it.
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Sdt<'a> {
+    /// `bytes` may run on past the end of the table.
+    pub fn parse(bytes: &'a [u8]) -> Option<Sdt<'a>> {
+        let header = SdtHeader::parse(bytes)?;
+        let length = header.length as usize;
+        if length < SdtHeader::SIZE {
+            return None;
+        }
+        let bytes = bytes.get(..length)?;
+        if checksum(bytes) != 0 {
+            return None;
+        }
+        Some(Sdt { header, bytes })
+    }
+
+    /// What comes after the header.
+    pub fn body(&self) -> &'a [u8] {
+        &self.bytes[SdtHeader::SIZE..]
+    }
+}
+
+/// The RSDT or XSDT: physical addresses of all the other tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootTable<'a> {
+    entries: &'a [u8],
+    /// 4 byte addresses in the RSDT, 8 in the XSDT.
+    entry_size: usize,
+}
+
+impl<'a> RootTable<'a> {
+    pub fn parse(table: &Sdt<'a>) -> Option<RootTable<'a>> {
+        let entry_size = match &table.header.signature {
+            b"RSDT" => 4,
+            b"XSDT" => 8,
+            _ => return None,
+        };
+        Some(RootTable {
+            entries: table.body(),
+            entry_size,
+        })
+    }
+
+    /// Physical addresses of the tables.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + 'a {
+        let entry_size = self.entry_size;
+        self.entries
+            .chunks_exact(entry_size)
+            .filter_map(move |entry| match entry_size {
+                4 => read_u32(entry, 0).map(u64::from),
+                _ => read_u64(entry, 0),
+            })
+    }
+}
+
+/// ACPI's Generic Address Structure: where a register is, and how to get at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    pub const SIZE: usize = 12;
+
+    pub fn parse(bytes: &[u8], at: usize) -> Option<GenericAddress> {
+        let space = match read_u8(bytes, at)? {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        Some(GenericAddress {
+            space,
+            bit_width: read_u8(bytes, at + 1)?,
+            bit_offset: read_u8(bytes, at + 2)?,
+            access_size: read_u8(bytes, at + 3)?,
+            address: read_u64(bytes, at + 4)?,
+        })
+    }
+}
+
+/// A CPU, from a Processor Local APIC (or x2APIC) entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// Matches the processor's object in the DSDT.
+    pub uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    /// If not `enabled`, whether it could be brought online later.
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt its inputs are wired to.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't wired to the I/O APIC input of the same number (or
+/// has a non-ISA polarity or trigger mode), e.g. the PIT's IRQ 0 on GSI 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Whatever's normal for the bus; active high for ISA.
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Whatever's normal for the bus; edge for ISA.
+    BusDefault,
+    Edge,
+    Level,
+}
+
+/// MPS INTI flags, used by overrides & NMI entries.
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    };
+    let trigger_mode = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    };
+    (polarity, trigger_mode)
+}
+
+/// Which local APIC input (LINT0/1) NMIs arrive on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// 0xff for all processors.
+    pub processor_uid: u8,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry<'a> {
+    Processor(Processor),
+    IoApic(IoApic),
+    InterruptOverride(InterruptOverride),
+    LocalApicNmi(LocalApicNmi),
+    /// 64 bit address of the local APICs, replacing the MADT's 32 bit one.
+    LocalApicAddressOverride(u64),
+    /// Some type we don't care about, or a malformed entry.
+    Other {
+        kind: u8,
+        data: &'a [u8],
+    },
+}
+
+/// The Multiple APIC Description Table (signature `APIC`): CPUs, I/O APICs &
+/// how ISA interrupts are wired up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Madt<'a> {
+    local_apic_address: u32,
+    flags: u32,
+    entries: &'a [u8],
+}
+
+impl<'a> Madt<'a> {
+    pub const SIGNATURE: &'static [u8; 4] = b"APIC";
+    /// Flag: there's also a pair of 8259 PICs, which must be masked to use the APICs.
+    const PCAT_COMPAT: u32 = 1 << 0;
+
+    pub fn parse(table: &Sdt<'a>) -> Option<Madt<'a>> {
+        if &table.header.signature != Self::SIGNATURE {
+            return None;
+        }
+        Some(Madt {
+            local_apic_address: read_u32(table.bytes, 36)?,
+            flags: read_u32(table.bytes, 40)?,
+            entries: table.bytes.get(44..)?,
+        })
+    }
+
+    /// Physical address of the local APICs' registers.
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride(addr) => Some(addr),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+
+    pub fn has_8259_pics(&self) -> bool {
+        self.flags & Self::PCAT_COMPAT != 0
+    }
+
+    pub fn entries(&self) -> MadtEntries<'a> {
+        MadtEntries {
+            bytes: self.entries,
+        }
+    }
+
+    pub fn processors(&self) -> impl Iterator<Item = Processor> + 'a {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::Processor(processor) => Some(processor),
+            _ => None,
+        })
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApic> + 'a {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::IoApic(io_apic) => Some(io_apic),
+            _ => None,
+        })
+    }
+
+    pub fn interrupt_overrides(&self) -> impl Iterator<Item = InterruptOverride> + 'a {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::InterruptOverride(over) => Some(over),
+            _ => None,
+        })
+    }
+
+    /// The global system interrupt ISA `irq` arrives on, after overrides.
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> u32 {
+        self.interrupt_overrides()
+            .find(|over| over.bus == 0 && over.irq == irq)
+            .map_or(irq as u32, |over| over.gsi)
+    }
+}
+
+/// Each MADT entry is a type byte, a length byte (of the whole entry), then data.
+#[derive(Debug, Clone)]
+pub struct MadtEntries<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for MadtEntries<'a> {
+    type Item = MadtEntry<'a>;
+
+    fn next(&mut self) -> Option<MadtEntry<'a>> {
+        let kind = read_u8(self.bytes, 0)?;
+        let length = read_u8(self.bytes, 1)? as usize;
+        // A zero length entry would have us loop forever.
+        let Some(entry) = self.bytes.get(..length).filter(|_| length >= 2) else {
+            self.bytes = &[];
+            return None;
+        };
+        self.bytes = &self.bytes[length..];
+        Some(parse_madt_entry(kind, entry).unwrap_or(MadtEntry::Other {
+            kind,
+            data: &entry[2..],
+        }))
+    }
+}
+
+fn parse_madt_entry(kind: u8, entry: &[u8]) -> Option<MadtEntry<'_>> {
+    Some(match kind {
+        0 => {
+            let flags = read_u32(entry, 4)?;
+            MadtEntry::Processor(Processor {
+                uid: read_u8(entry, 2)? as u32,
+                apic_id: read_u8(entry, 3)? as u32,
+                enabled: flags & 1 != 0,
+                online_capable: flags & 2 != 0,
+            })
+        }
+        1 => MadtEntry::IoApic(IoApic {
+            id: read_u8(entry, 2)?,
+            address: read_u32(entry, 4)?,
+            gsi_base: read_u32(entry, 8)?,
+        }),
+        2 => {
+            let (polarity, trigger_mode) = inti_flags(read_u16(entry, 8)?);
+            MadtEntry::InterruptOverride(InterruptOverride {
+                bus: read_u8(entry, 2)?,
+                irq: read_u8(entry, 3)?,
+                gsi: read_u32(entry, 4)?,
+                polarity,
+                trigger_mode,
+            })
+        }
+        4 => {
+            let (polarity, trigger_mode) = inti_flags(read_u16(entry, 3)?);
+            MadtEntry::LocalApicNmi(LocalApicNmi {
+                processor_uid: read_u8(entry, 2)?,
+                lint: read_u8(entry, 5)?,
+                polarity,
+                trigger_mode,
+            })
+        }
+        5 => MadtEntry::LocalApicAddressOverride(read_u64(entry, 4)?),
+        9 => {
+            let flags = read_u32(entry, 8)?;
+            MadtEntry::Processor(Processor {
+                uid: read_u32(entry, 12)?,
+                apic_id: read_u32(entry, 4)?,
+                enabled: flags & 1 != 0,
+                online_capable: flags & 2 != 0,
+            })
+        }
+        _ => return None,
+    })
+}
+
+/// The Fixed ACPI Description Table (signature `FACP`): power management
+/// registers, the reset register & where the DSDT is. Only the parts we use.
+/// - Note: we take the PM1 blocks' 32 bit port numbers, not the `X_` generic
+///   addresses; PCs (and QEMU) put them in I/O space either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Physical address of the DSDT.
+    pub dsdt_address: u64,
+    /// The (ISA) IRQ ACPI events arrive on.
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` to, to switch from legacy to ACPI mode; 0 if
+    /// the machine is always in ACPI mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm1_event_length: u8,
+    /// Port of the 3.579545 MHz ACPI PM timer; 0 if there isn't one.
+    pub pm_timer_block: u32,
+    /// CMOS RAM index of the RTC's century register; 0 if there isn't one.
+    pub century_register: u8,
+    /// `IAPC_BOOT_ARCH`: which legacy devices are there.
+    pub boot_arch_flags: u16,
+    pub flags: u32,
+    /// Where to write `reset_value` to reset the machine, if it can be done
+    /// that way.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub const SIGNATURE: &'static [u8; 4] = b"FACP";
+    /// Flag: the PM timer is 32 bits wide, not 24.
+    pub const TMR_VAL_EXT: u32 = 1 << 8;
+    /// Flag: `reset_register` works.
+    pub const RESET_REG_SUP: u32 = 1 << 10;
+    /// Boot arch flag: there's an 8042 keyboard controller.
+    pub const BOOT_ARCH_8042: u16 = 1 << 1;
+    /// Boot arch flag: there's no CMOS RTC.
+    pub const BOOT_ARCH_NO_CMOS_RTC: u16 = 1 << 5;
+
+    pub fn parse(table: &Sdt) -> Option<Fadt> {
+        if &table.header.signature != Self::SIGNATURE {
+            return None;
+        }
+        let bytes = table.bytes;
+        // Later revisions only ever add fields on the end, so anything past
+        // the end of an old table reads as 0.
+        let u8_at = |at| read_u8(bytes, at).unwrap_or(0);
+        let u16_at = |at| read_u16(bytes, at).unwrap_or(0);
+        let u32_at = |at| read_u32(bytes, at).unwrap_or(0);
+        let flags = u32_at(112);
+        let dsdt_address = match read_u64(bytes, 140).unwrap_or(0) {
+            0 => u32_at(40) as u64,
+            x_dsdt => x_dsdt,
+        };
+        let reset_register = GenericAddress::parse(bytes, 116)
+            .filter(|_| flags & Self::RESET_REG_SUP != 0 && bytes.len() > 128);
+        Some(Fadt {
+            dsdt_address,
+            sci_interrupt: u16_at(46),
+            smi_command_port: u32_at(48),
+            acpi_enable: u8_at(52),
+            acpi_disable: u8_at(53),
+            pm1a_event_block: u32_at(56),
+            pm1b_event_block: u32_at(60),
+            pm1a_control_block: u32_at(64),
+            pm1b_control_block: u32_at(68),
+            pm1_event_length: u8_at(88),
+            pm_timer_block: u32_at(76),
+            century_register: u8_at(108),
+            boot_arch_flags: u16_at(109),
+            flags,
+            reset_register,
+            reset_value: u8_at(128),
+        })
+    }
+}
+
+/// The HPET Description Table (signature `HPET`): where the High Precision
+/// Event Timer's registers are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    /// Number of timers (comparators) in this block.
+    pub comparator_count: u8,
+    pub counter_64bit: bool,
+    /// Whether it can take over the PIT's & RTC's IRQs 0 and 8.
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub base_address: GenericAddress,
+    pub number: u8,
+    /// Smallest period, in counter ticks, it can do periodic interrupts at
+    /// without losing any.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub const SIGNATURE: &'static [u8; 4] = b"HPET";
+
+    pub fn parse(table: &Sdt) -> Option<Hpet> {
+        if &table.header.signature != Self::SIGNATURE {
+            return None;
+        }
+        let bytes = table.bytes;
+        let id = read_u32(bytes, 36)?;
+        Some(Hpet {
+            hardware_revision: id as u8,
+            comparator_count: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            base_address: GenericAddress::parse(bytes, 40)?,
+            number: read_u8(bytes, 52)?,
+            minimum_tick: read_u16(bytes, 53)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make `table`'s checksum work out.
+    fn fix_checksum(table: &mut [u8]) {
+        table[9] = 0;
+        table[9] = 0u8.wrapping_sub(checksum(table));
+    }
+
+    #[test]
+    fn test_rsdp_checksum() {
+        let mut bytes = [0u8; Rsdp::V1_SIZE];
+        bytes[..8].copy_from_slice(Rsdp::SIGNATURE);
+        bytes[9..15].copy_from_slice(b"BOCHS ");
+        bytes[16..20].copy_from_slice(&0x7fe1_4d2cu32.to_le_bytes());
+        bytes[8] = 0u8.wrapping_sub(checksum(&bytes));
+
+        let rsdp = Rsdp::parse(&bytes).unwrap();
+        assert_eq!(rsdp.revision, 0);
+        assert_eq!(&rsdp.oem_id, b"BOCHS ");
+        assert_eq!(rsdp.rsdt_address, 0x7fe1_4d2c);
+        assert_eq!(rsdp.xsdt_address, None);
+        bytes[19] ^= 1;
+        assert_eq!(Rsdp::parse(&bytes), None);
+    }
+
+    #[test]
+    fn test_madt_entries_stop_at_bad_length() {
+        let mut bytes = [0u8; SdtHeader::SIZE + 8 + 12 + 4];
+        let length = bytes.len() as u32;
+        bytes[..4].copy_from_slice(b"APIC");
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        // An I/O APIC, then an entry claiming to be 0 bytes long.
+        let entries = SdtHeader::SIZE + 8;
+        bytes[entries..entries + 12].copy_from_slice(&[1, 12, 7, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+        fix_checksum(&mut bytes);
+
+        let madt = Madt::parse(&Sdt::parse(&bytes).unwrap()).unwrap();
+        let io_apic = IoApic {
+            id: 7,
+            address: 0xfec0_0000,
+            gsi_base: 0,
+        };
+        assert!(madt.entries().eq([MadtEntry::IoApic(io_apic)]));
+    }
+}
@@ -0,0 +1,98 @@
+//! aml.rs
+//! Just enough of [AML](https://uefi.org/specs/ACPI/6.5/20_AML_Specification.html),
+//! the bytecode in the DSDT, to power the machine off. A real AML interpreter
+//! is a big job; for S5 (soft off) all we need is the package the firmware
+//! declares with `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })`, which
+//! is nearly always plain data we can pick out of the bytes directly.
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_CHAR: u8 = b'\\';
+
+/// Read an integer constant, returning it & how many bytes it took up.
+fn read_integer(aml: &[u8]) -> Option<(u64, usize)> {
+    let read = |len: usize| {
+        let bytes = aml.get(1..1 + len)?;
+        let value = bytes
+            .iter()
+            .rev()
+            .fold(0u64, |value, &byte| value << 8 | byte as u64);
+        Some((value, 1 + len))
+    };
+    match *aml.first()? {
+        0x00 => Some((0, 1)),        // ZeroOp
+        0x01 => Some((1, 1)),        // OneOp
+        0xff => Some((u64::MAX, 1)), // OnesOp
+        0x0a => read(1),             // BytePrefix
+        0x0b => read(2),             // WordPrefix
+        0x0c => read(4),             // DWordPrefix
+        0x0e => read(8),             // QWordPrefix
+        _ => None,
+    }
+}
+
+/// Skip a PkgLength: the lead byte's top 2 bits say how many more bytes follow.
+fn skip_pkg_length(aml: &[u8]) -> Option<&[u8]> {
+    let extra = (*aml.first()? >> 6) as usize;
+    aml.get(1 + extra..)
+}
+
+/// The values to write to PM1a's & PM1b's SLP_TYP field to enter S5, from the
+/// DSDT's body.
+pub fn s5_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    let mut start = 0;
+    while let Some(found) = aml
+        .get(start..)?
+        .windows(4)
+        .position(|name| name == b"_S5_")
+    {
+        let at = start + found;
+        start = at + 1;
+        // `Name (_S5, ...)` or `Name (\_S5, ...)`; anything else is just a
+        // reference to it, or bytes that happen to look like it.
+        let declared = match at.checked_sub(1).map(|i| aml[i]) {
+            Some(NAME_OP) => true,
+            Some(ROOT_CHAR) => at >= 2 && aml[at - 2] == NAME_OP,
+            _ => false,
+        };
+        let rest = &aml[at + 4..];
+        if !declared || rest.first() != Some(&PACKAGE_OP) {
+            continue;
+        }
+        let package = skip_pkg_length(&rest[1..])?;
+        let (&count, elements) = package.split_first()?;
+        if count == 0 {
+            continue;
+        }
+        let (slp_typ_a, len) = read_integer(elements)?;
+        // Some firmware only gives the one value.
+        let slp_typ_b = match count {
+            1 => 0,
+            _ => read_integer(&elements[len..])?.0,
+        };
+        return Some((slp_typ_a as u8, slp_typ_b as u8));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_s5_sleep_types() {
+        // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let aml = b"\x10\x05\\_S5_\x08_S5_\x12\x0a\x04\x0a\x05\x0a\x05\x00\x00";
+        assert_eq!(s5_sleep_types(aml), Some((5, 5)));
+        // Only a reference to it
+        assert_eq!(s5_sleep_types(b"\x70_S5_\x12\x03\x01\x00"), None);
+    }
+
+    #[test]
+    fn test_s5_with_one_value() {
+        // Name (\_S5, Package (0x01) { 0x07 })
+        let aml = b"\x08\\_S5_\x12\x04\x01\x0a\x07";
+        assert_eq!(s5_sleep_types(aml), Some((7, 0)));
+        assert_eq!(s5_sleep_types(b"\x08_S5_\x12\x02\x00"), None);
+    }
+}
@@ -0,0 +1,191 @@
+//! gdb.rs
+//! Parsing for the [GDB Remote Serial Protocol](https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html),
+//! used by the kernel's GDB stub. Packets look like `$<data>#<checksum>`, where
+//! the checksum is the sum of the data bytes mod 256, as two hex digits.
+
+/// What a complete packet turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    /// A packet with a good checksum, this many bytes long.
+    Packet(usize),
+    /// Bad checksum, or too long for the buffer; GDB should resend it.
+    Corrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Skipping acks, Ctrl-Cs, and line noise until a `$`.
+    Idle,
+    Data,
+    /// After the `#`, with the first checksum digit once we have it.
+    Checksum(Option<u8>),
+}
+
+/// Picks packets out of a byte stream, one byte at a time.
+#[derive(Debug, Clone)]
+pub struct PacketDecoder {
+    state: State,
+    len: usize,
+    overflowed: bool,
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketDecoder {
+    /// A decoder waiting for the start of a packet.
+    pub const fn new() -> Self {
+        PacketDecoder {
+            state: State::Idle,
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// A decoder for when the caller already consumed the packet's `$`.
+    pub const fn after_start() -> Self {
+        PacketDecoder {
+            state: State::Data,
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Feed in the next byte, collecting packet data into `buf`. Returns what
+    /// the packet was once its last byte arrives; its data is `buf[..len]`.
+    pub fn push(&mut self, byte: u8, buf: &mut [u8]) -> Option<Decoded> {
+        match self.state {
+            State::Idle => {
+                if byte == b'$' {
+                    *self = Self::after_start();
+                }
+                None
+            }
+            State::Data if byte == b'#' => {
+                self.state = State::Checksum(None);
+                None
+            }
+            State::Data => {
+                match buf.get_mut(self.len) {
+                    Some(slot) => {
+                        *slot = byte;
+                        self.len += 1;
+                    }
+                    None => self.overflowed = true,
+                }
+                None
+            }
+            State::Checksum(None) => {
+                self.state = State::Checksum(Some(byte));
+                None
+            }
+            State::Checksum(Some(first)) => {
+                let expected = parse_hex(&[first, byte]);
+                let decoded = match buf.get(..self.len) {
+                    Some(data) if !self.overflowed && expected == Some(checksum(data) as u64) => {
+                        Decoded::Packet(self.len)
+                    }
+                    _ => Decoded::Corrupt,
+                };
+                *self = Self::new();
+                Some(decoded)
+            }
+        }
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// A big-endian hex number, like the addresses and lengths in packets.
+pub fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0, |value, &digit| {
+        let digit = (digit as char).to_digit(16)?;
+        Some(value << 4 | digit as u64)
+    })
+}
+
+/// Little-endian hex pairs, like register values, into a number.
+pub fn decode_le(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks_exact(2)
+        .enumerate()
+        .try_fold(0, |value, (i, pair)| {
+            Some(value | parse_hex(pair)? << (i * 8))
+        })
+}
+
+pub fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let i = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..i], &bytes[i + 1..]))
+}
+
+/// The `addr,length` arguments of memory packets.
+pub fn parse_addr_len(args: &[u8]) -> Option<(u64, usize)> {
+    let (addr, len) = split_once(args, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)? as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `bytes` to a new decoder; what the last packet decoded to.
+    fn decode(bytes: &[u8], buf: &mut [u8]) -> Option<Decoded> {
+        let mut decoder = PacketDecoder::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.push(byte, buf))
+            .last()
+    }
+
+    #[test]
+    fn test_checksum() {
+        // From the GDB docs' example exchange
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex(b"ffff8000001a2b3c"), Some(0xffff8000001a2b3c));
+        assert_eq!(parse_hex(b"0"), Some(0));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"12g"), None);
+        assert_eq!(parse_hex(b"10000000000000000"), None);
+    }
+
+    #[test]
+    fn test_decode_little_endian_register() {
+        assert_eq!(decode_le(b"78563412"), Some(0x12345678));
+        assert_eq!(decode_le(b"xxxxxxxx"), None);
+        assert_eq!(decode_le(b"123"), None);
+    }
+
+    #[test]
+    fn test_parse_addr_len() {
+        assert_eq!(parse_addr_len(b"b8000,10"), Some((0xb8000, 16)));
+        assert_eq!(parse_addr_len(b"b8000"), None);
+    }
+
+    #[test]
+    fn test_packet_decoder() {
+        let mut buf = [0u8; 4];
+        // Acks & noise before the packet are skipped.
+        assert_eq!(decode(b"+-x$OK#9a", &mut buf), Some(Decoded::Packet(2)));
+        assert_eq!(&buf[..2], b"OK");
+        assert_eq!(decode(b"$OK#00", &mut buf), Some(Decoded::Corrupt));
+        // Too long for the buffer
+        assert_eq!(decode(b"$OKOK#34", &mut buf[..3]), Some(Decoded::Corrupt));
+        assert_eq!(decode(b"$OK#9", &mut buf), None);
+    }
+}
@@ -0,0 +1,173 @@
+//! ksyms.rs
+//! The kernel symbol table format, which `tools/ksyms.rs` writes into the
+//! kernel image after linking. Lets us turn addresses into `function+offset`.
+//!
+//! Table layout, all little endian:
+//! - Header: magic `KSYM`, u32 symbol count, u32 offset of the name pool, u32 reserved
+//! - Entries, sorted by address: u64 address, u32 size, u32 name offset into the pool
+//! - Name pool: NUL terminated (mangled) symbol names
+
+use crate::bytes::{read_u32, read_u64};
+use core::fmt;
+
+pub const MAGIC: &[u8; 4] = b"KSYM";
+pub const HEADER_SIZE: usize = 16;
+pub const ENTRY_SIZE: usize = 16;
+
+/// A function symbol from the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub address: u64,
+    pub size: u32,
+    /// Still mangled; `Display` demangles it.
+    pub name: &'a str,
+}
+
+impl fmt::Display for Symbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // `{:#}` leaves off the trailing hash (e.g. `::h0123456789abcdef`).
+        write!(f, "{:#}", rustc_demangle::demangle(self.name))
+    }
+}
+
+/// A view over an encoded symbol table.
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Check the header & bounds of an encoded table. `None` if `bytes` is
+    /// malformed or was never filled in.
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            return None;
+        }
+        let count = read_u32(bytes, 4)? as usize;
+        let names_offset = read_u32(bytes, 8)? as usize;
+        let entries_end = count.checked_mul(ENTRY_SIZE)?.checked_add(HEADER_SIZE)?;
+        if entries_end > names_offset || names_offset > bytes.len() {
+            return None;
+        }
+        Some(SymbolTable {
+            entries: &bytes[HEADER_SIZE..entries_end],
+            names: &bytes[names_offset..],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn address(&self, index: usize) -> u64 {
+        read_u64(self.entries, index * ENTRY_SIZE).unwrap_or(0)
+    }
+
+    fn symbol(&self, index: usize) -> Option<Symbol<'a>> {
+        let entry = index * ENTRY_SIZE;
+        let name_start = read_u32(self.entries, entry + 12)? as usize;
+        let name = self.names.get(name_start..)?;
+        let name_len = name.iter().position(|&b| b == 0)?;
+        Some(Symbol {
+            address: self.address(index),
+            size: read_u32(self.entries, entry + 8)?,
+            name: core::str::from_utf8(&name[..name_len]).ok()?,
+        })
+    }
+
+    /// The symbol `addr` falls inside, and how far into it `addr` is.
+    pub fn lookup(&self, addr: u64) -> Option<(Symbol<'a>, u64)> {
+        // Binary search for the last symbol starting at or below `addr`.
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.address(mid) <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let symbol = self.symbol(low.checked_sub(1)?)?;
+        let offset = addr - symbol.address;
+        // Symbols with no size (e.g. from assembly) get the benefit of the doubt.
+        if symbol.size != 0 && offset >= symbol.size as u64 {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    /// Encode `(address, size, name)` symbols the way `tools/ksyms.rs` does.
+    fn encode(symbols: &[(u64, u32, &str)], out: &mut [u8]) {
+        let names_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+        out[..4].copy_from_slice(MAGIC);
+        out[4..8].copy_from_slice(&(symbols.len() as u32).to_le_bytes());
+        out[8..12].copy_from_slice(&(names_offset as u32).to_le_bytes());
+        let mut name_at = 0;
+        for (i, &(address, size, name)) in symbols.iter().enumerate() {
+            let entry = HEADER_SIZE + i * ENTRY_SIZE;
+            out[entry..entry + 8].copy_from_slice(&address.to_le_bytes());
+            out[entry + 8..entry + 12].copy_from_slice(&size.to_le_bytes());
+            out[entry + 12..entry + 16].copy_from_slice(&(name_at as u32).to_le_bytes());
+            let start = names_offset + name_at;
+            out[start..start + name.len()].copy_from_slice(name.as_bytes());
+            name_at += name.len() + 1;
+        }
+    }
+
+    #[test]
+    fn test_lookup_finds_containing_symbol() {
+        let mut bytes = [0u8; 256];
+        encode(
+            &[
+                (0x1000, 0x20, "first"),
+                (0x1020, 0x10, "second"),
+                (0x2000, 0, "asm"),
+            ],
+            &mut bytes,
+        );
+        let table = SymbolTable::parse(&bytes).unwrap();
+        assert_eq!(table.len(), 3);
+
+        assert_eq!(table.lookup(0xfff), None);
+        let (symbol, offset) = table.lookup(0x101f).unwrap();
+        assert_eq!((symbol.name, offset), ("first", 0x1f));
+        let (symbol, offset) = table.lookup(0x1020).unwrap();
+        assert_eq!((symbol.name, offset), ("second", 0));
+        // In the gap after `second`
+        assert_eq!(table.lookup(0x1030), None);
+        // Sizeless symbols extend up to the next one
+        let (symbol, offset) = table.lookup(0x2345).unwrap();
+        assert_eq!((symbol.name, offset), ("asm", 0x345));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_tables() {
+        assert!(SymbolTable::parse(&[0; 64]).is_none());
+        let mut bytes = [0u8; 64];
+        encode(&[(0x1000, 0x20, "first")], &mut bytes);
+        // Claim more entries than fit before the name pool.
+        bytes[4] = 3;
+        assert!(SymbolTable::parse(&bytes).is_none());
+    }
+
+    #[test]
+    fn test_symbol_display_demangles() {
+        let symbol = Symbol {
+            address: 0,
+            size: 0,
+            name: "_ZN16thompson_rust_os5ksyms6lookup17h0123456789abcdefE",
+        };
+        assert_eq!(symbol.to_string(), "thompson_rust_os::ksyms::lookup");
+    }
+}
@@ -0,0 +1,16 @@
+//! lib.rs
+//! Parsers for data the kernel gets from outside, kept apart from the kernel so
+//! they build for the host too, where they can be fuzzed (see `fuzz/`). Nothing
+//! in here touches hardware or global state: bytes in, values out.
+
+#![no_std]
+
+// Tests run on the host, which has `std`.
+#[cfg(test)]
+extern crate std;
+
+pub mod acpi;
+pub mod aml;
+mod bytes;
+pub mod gdb;
+pub mod ksyms;
@@ -0,0 +1,211 @@
+//! acpi.rs
+//! Finds the firmware's ACPI tables, which are how we learn what the machine
+//! looks like: how many CPUs, where the APICs & HPET are, and how to power it
+//! off or reset it. The byte-level parsing lives in the `parse` crate (so it
+//! can be fuzzed); this is the part that goes looking in physical memory.
+//!
+//! BIOSes put the RSDP (the pointer to everything else) in one of two places,
+//! on a 16 byte boundary:
+//! - the first KiB of the Extended BIOS Data Area, whose segment is at 0x40e
+//! - the BIOS ROM area, 0xe0000 to 0xfffff
+//!
+//! - Note: requires `memory::init` to have been called.
+
+use crate::memory;
+use spin::Mutex;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::VirtAddr;
+
+pub use thompson_rust_os_parse::acpi::{
+    AddressSpace, Fadt, GenericAddress, Hpet, InterruptOverride, IoApic, Madt, MadtEntry, Polarity,
+    Processor, RootTable, Rsdp, Sdt, SdtHeader, TriggerMode,
+};
+
+/// Where the BIOS keeps the EBDA's real mode segment.
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+const EBDA_SEARCH_LEN: usize = 1024;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid RSDP anywhere we looked.
+    RsdpNotFound,
+    /// The RSDP points at something that isn't a valid RSDT/XSDT.
+    BadRootTable(u64),
+}
+
+/// What we found, ready for other modules to use.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTables {
+    pub rsdp: Rsdp,
+    pub root: RootTable<'static>,
+    pub madt: Option<Madt<'static>>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    /// The Differentiated System Description Table, with the AML code that
+    /// describes the rest of the hardware.
+    pub dsdt: Option<Sdt<'static>>,
+}
+
+static TABLES: Mutex<Option<AcpiTables>> = Mutex::new(None);
+
+/// The tables `init` found, if it has been called & found any.
+pub fn tables() -> Option<AcpiTables> {
+    *TABLES.lock()
+}
+
+/// Borrow `len` bytes of physical memory starting at `addr`, if they're all mapped.
+fn phys_bytes(addr: u64, len: usize) -> Option<&'static [u8]> {
+    // Addresses come from the firmware, so don't trust them not to overflow.
+    let start = memory::physical_memory_offset()
+        .as_u64()
+        .checked_add(addr)?;
+    let start = VirtAddr::try_new(start).ok()?;
+    let end = VirtAddr::try_new(start.as_u64().checked_add(len as u64)?).ok()?;
+    // Tables can straddle pages; check each one.
+    let mut page = start.align_down(Size4KiB::SIZE);
+    while page < end {
+        memory::translate_addr(page)?;
+        page += Size4KiB::SIZE;
+    }
+    Some(unsafe { core::slice::from_raw_parts(start.as_ptr(), len) })
+}
+
+/// Read & validate the table at physical address `addr`.
+fn map_table(addr: u64) -> Option<Sdt<'static>> {
+    let header = SdtHeader::parse(phys_bytes(addr, SdtHeader::SIZE)?)?;
+    Sdt::parse(phys_bytes(addr, header.length as usize)?)
+}
+
+/// Look for the RSDP in `len` bytes of physical memory from `start`.
+fn search_rsdp(start: u64, len: usize) -> Option<Rsdp> {
+    let area = phys_bytes(start, len)?;
+    (0..len)
+        .step_by(Rsdp::ALIGN)
+        .find_map(|offset| Rsdp::parse(&area[offset..]))
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    let segment = phys_bytes(EBDA_SEGMENT_POINTER, 2)?;
+    let ebda = (u16::from_le_bytes([segment[0], segment[1]]) as u64) << 4;
+    let in_ebda = if ebda != 0 {
+        search_rsdp(ebda, EBDA_SEARCH_LEN)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| search_rsdp(BIOS_AREA_START, (BIOS_AREA_END - BIOS_AREA_START) as usize))
+}
+
+/// Find the table with `signature` (e.g. `b"APIC"`), if the firmware provided one.
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt<'static>> {
+    tables()?
+        .root
+        .iter()
+        .filter_map(map_table)
+        .find(|table| &table.header.signature == signature)
+}
+
+/// Find & parse the ACPI tables. Anything malformed is skipped with a warning.
+pub fn init() -> Result<AcpiTables, AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    // ACPI 2.0+ says to prefer the XSDT, which can point above 4 GiB.
+    let root_address = rsdp.xsdt_address.unwrap_or(rsdp.rsdt_address as u64);
+    let root = map_table(root_address)
+        .as_ref()
+        .and_then(RootTable::parse)
+        .ok_or(AcpiError::BadRootTable(root_address))?;
+
+    let mut tables = AcpiTables {
+        rsdp,
+        root,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        dsdt: None,
+    };
+    for addr in root.iter() {
+        let Some(table) = map_table(addr) else {
+            log::warn!("acpi: skipping bad table at {:#x}", addr);
+            continue;
+        };
+        match &table.header.signature {
+            Madt::SIGNATURE => tables.madt = Madt::parse(&table),
+            Fadt::SIGNATURE => tables.fadt = Fadt::parse(&table),
+            Hpet::SIGNATURE => tables.hpet = Hpet::parse(&table),
+            _ => {}
+        }
+    }
+    // The DSDT isn't in the root table; only the FADT knows where it is.
+    tables.dsdt = tables
+        .fadt
+        .and_then(|fadt| map_table(fadt.dsdt_address))
+        .filter(|dsdt| &dsdt.header.signature == b"DSDT");
+
+    *TABLES.lock() = Some(tables);
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // QEMU's PC machines always have ACPI tables, an I/O APIC & an HPET.
+
+    #[test_case]
+    fn test_finds_tables() {
+        let tables = init().unwrap();
+        assert!(tables.root.iter().count() >= 2);
+        let madt = tables.madt.unwrap();
+        assert!(madt.processors().any(|cpu| cpu.enabled));
+        assert_eq!(madt.io_apics().count(), 1);
+        // The PIT's IRQ 0 is wired to the I/O APIC's input 2.
+        assert_eq!(madt.isa_irq_to_gsi(0), 2);
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+
+        let fadt = tables.fadt.unwrap();
+        assert_ne!(fadt.pm1a_control_block, 0);
+        assert!(tables.dsdt.is_some());
+        assert_eq!(tables.hpet.unwrap().base_address.address, 0xfed0_0000);
+        assert!(find_table(b"FACP").is_some());
+        assert!(find_table(b"NOPE").is_none());
+    }
+
+    /// Make `table`'s checksum work out.
+    fn fix_checksum(table: &mut [u8]) {
+        table[9] = 0;
+        table[9] = 0u8.wrapping_sub(thompson_rust_os_parse::acpi::checksum(table));
+    }
+
+    #[test_case]
+    fn test_fuzz_acpi_tables() {
+        crate::fuzz::run(|input| {
+            Rsdp::parse(input);
+            thompson_rust_os_parse::aml::s5_sleep_types(input);
+            if input.len() < SdtHeader::SIZE {
+                return;
+            }
+            // Get past the header checks, so the interesting parts see the input.
+            let mut bytes = [0u8; crate::fuzz::MAX_INPUT_LEN];
+            let bytes = &mut bytes[..input.len()];
+            bytes.copy_from_slice(input);
+            let length = bytes.len() as u32;
+            bytes[4..8].copy_from_slice(&length.to_le_bytes());
+            for signature in [b"APIC", b"FACP", b"HPET", b"XSDT"] {
+                bytes[..4].copy_from_slice(signature);
+                fix_checksum(bytes);
+                let table = Sdt::parse(bytes).unwrap();
+                if let Some(madt) = Madt::parse(&table) {
+                    madt.entries().count();
+                    madt.local_apic_address();
+                    madt.isa_irq_to_gsi(0);
+                }
+                Fadt::parse(&table);
+                Hpet::parse(&table);
+                if let Some(root) = RootTable::parse(&table) {
+                    root.iter().count();
+                }
+            }
+        });
+    }
+}
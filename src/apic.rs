@@ -0,0 +1,316 @@
+//! apic.rs
+//! Driver for the CPU's local APIC timer. Unlike the PIT (or the HPET), each
+//! CPU has its own, so it's what a scheduler ticks off of.
+//!
+//! The timer counts down from an initial count at the bus clock (divided by
+//! `DIVIDE_BY_16`), which varies from machine to machine, so `init` measures it
+//! (and the TSC's rate) against a clock we do know: the HPET if there is one,
+//! else the PIT. Then it can run:
+//! - periodically, reloading the initial count each time it hits 0
+//! - one-shot, stopping at 0
+//! - against a TSC deadline, interrupting once the TSC passes a value we set
+//!   (on CPUs with `TSC-deadline` in CPUID)
+//!
+//! While it's running, the PIT's IRQ 0 is masked and `APIC_TIMER_VECTOR` ticks
+//! `interrupts::ticks()` instead.
+//! - Note: requires `acpi::init` to have been called.
+
+use crate::interrupts::{self, APIC_TIMER_VECTOR, SPURIOUS_VECTOR};
+use crate::time::TimerMode;
+use crate::{acpi, hpet, memory};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+/// Local APIC registers, as offsets from its base address.
+const ID: usize = 0x020;
+const EOI: usize = 0x0b0;
+const SPURIOUS_INTERRUPT: usize = 0x0f0;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const INITIAL_COUNT: usize = 0x380;
+const CURRENT_COUNT: usize = 0x390;
+const DIVIDE_CONFIGURATION: usize = 0x3e0;
+const REGISTERS_SIZE: u64 = 0x400;
+
+/// Spurious interrupt register: software enable.
+const APIC_ENABLE: u32 = 1 << 8;
+/// LVT bits.
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+const LVT_EXT_INT: u32 = 0b111 << 8;
+const LVT_NMI: u32 = 0b100 << 8;
+/// Divide configuration value for dividing the bus clock by 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+/// CPUID leaf 1, ECX: the timer supports TSC-deadline mode.
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// Fewest counts the timer's started with, so a silly `hz` or `nanos` can't
+/// have it interrupt again before the last one's handled.
+const MIN_COUNTS: u128 = 100;
+/// How long to measure the timer's rate for.
+const CALIBRATION_MICROS: u64 = 10_000;
+const PIT_HZ: u64 = 1_193_182;
+
+/// Virtual address of the registers; 0 until `init`.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Timer counts per second, at our divider.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// No ACPI `APIC` table to say where it is.
+    NoMadt,
+    /// The registers aren't in the bootloader's physical memory mapping.
+    Unmapped(u64),
+    /// `init` hasn't been called.
+    NotInitialized,
+    /// This CPU's timer can't do TSC-deadline mode.
+    NoTscDeadline,
+}
+
+fn base() -> Result<u64, ApicError> {
+    match BASE.load(Ordering::Relaxed) {
+        0 => Err(ApicError::NotInitialized),
+        base => Ok(base),
+    }
+}
+
+fn read(base: u64, register: usize) -> u32 {
+    unsafe { ((base + register as u64) as *const u32).read_volatile() }
+}
+
+fn write(base: u64, register: usize, value: u32) {
+    unsafe { ((base + register as u64) as *mut u32).write_volatile(value) }
+}
+
+/// The time stamp counter.
+pub fn tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn supports_tsc_deadline() -> bool {
+    // `__cpuid` stopped being unsafe in newer toolchains.
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.ecx & CPUID_TSC_DEADLINE != 0
+}
+
+/// Busy wait `micros` microseconds (up to ~54ms) with PIT channel 2, which is
+/// free b/c it only drives the PC speaker.
+fn pit_wait(micros: u64) {
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    let count = (PIT_HZ * micros / 1_000_000).min(0xffff) as u16;
+    unsafe {
+        // Speaker off, gate off while we set it up.
+        let speaker = gate.read() & !0b11;
+        gate.write(speaker);
+        // Channel 2, low then high byte, mode 0 (interrupt on terminal count).
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        // Raising the gate starts counting; bit 5 goes high when it's done.
+        gate.write(speaker | 1);
+        while gate.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        gate.write(speaker);
+    }
+}
+
+/// Busy wait `micros` microseconds against the best clock we have.
+fn reference_wait(micros: u64) {
+    if hpet::is_present() {
+        let until = hpet::nanos() + micros * 1000;
+        while hpet::nanos() < until {
+            core::hint::spin_loop();
+        }
+    } else {
+        pit_wait(micros);
+    }
+}
+
+/// Measure how fast the timer counts down, & how fast the TSC counts up.
+fn calibrate(base: u64) {
+    write(base, DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+    write(base, LVT_TIMER, LVT_MASKED | APIC_TIMER_VECTOR as u32);
+    let tsc_start = tsc();
+    write(base, INITIAL_COUNT, u32::MAX);
+    reference_wait(CALIBRATION_MICROS);
+    let remaining = read(base, CURRENT_COUNT);
+    let tsc_elapsed = tsc() - tsc_start;
+    write(base, INITIAL_COUNT, 0);
+
+    let per_second = 1_000_000 / CALIBRATION_MICROS;
+    TIMER_FREQUENCY.store(
+        (u32::MAX - remaining) as u64 * per_second,
+        Ordering::Relaxed,
+    );
+    TSC_HZ.store(tsc_elapsed * per_second, Ordering::Relaxed);
+}
+
+/// Find & enable this CPU's local APIC, and calibrate its timer.
+pub fn init() -> Result<(), ApicError> {
+    let madt = acpi::tables()
+        .and_then(|tables| tables.madt)
+        .ok_or(ApicError::NoMadt)?;
+    let address = madt.local_apic_address();
+    let phys = PhysAddr::try_new(address).map_err(|_| ApicError::Unmapped(address))?;
+    let base = memory::map_mmio(phys, REGISTERS_SIZE)
+        .ok_or(ApicError::Unmapped(address))?
+        .as_u64();
+
+    // "Virtual wire" mode: the 8259 PICs' interrupts keep coming in through
+    // LINT0, & NMIs through LINT1, like before we enabled it.
+    write(base, LVT_LINT0, LVT_EXT_INT);
+    write(base, LVT_LINT1, LVT_NMI);
+    write(
+        base,
+        SPURIOUS_INTERRUPT,
+        APIC_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+    calibrate(base);
+    BASE.store(base, Ordering::Relaxed);
+    Ok(())
+}
+
+/// This CPU's local APIC ID.
+pub fn id() -> Result<u32, ApicError> {
+    Ok(read(base()?, ID) >> 24)
+}
+
+/// CPUs that per-CPU statistics (`idle`, `irq_stats`) are kept for.
+pub const MAX_CPUS: usize = 16;
+
+/// Which CPU we're on, for indexing per-CPU statistics: our local APIC ID, or
+/// 0 before `init`. IDs past `MAX_CPUS` share the last index.
+pub fn cpu_index() -> usize {
+    id().map_or(0, |id| id as usize).min(MAX_CPUS - 1)
+}
+
+/// Timer counts per second; 0 before `init`.
+pub fn timer_frequency() -> u64 {
+    TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// TSC counts per second; 0 before `init`.
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// The TSC value `nanos` nanoseconds from now, for `start_tsc_deadline_timer`.
+pub fn tsc_after(nanos: u64) -> u64 {
+    tsc() + (nanos as u128 * tsc_frequency() as u128 / 1_000_000_000) as u64
+}
+
+/// Tell the local APIC we've handled its interrupt.
+pub fn end_of_interrupt() {
+    if let Ok(base) = base() {
+        write(base, EOI, 0);
+    }
+}
+
+/// Whether the timer is standing in for the PIT.
+pub fn is_timer_running() -> bool {
+    TIMER_RUNNING.load(Ordering::Relaxed)
+}
+
+/// Start the timer, in place of the PIT.
+pub fn start_timer(mode: TimerMode) -> Result<(), ApicError> {
+    let base = base()?;
+    let vector = APIC_TIMER_VECTOR as u32;
+    let counts_after = |nanos: u64| {
+        let counts = nanos as u128 * timer_frequency() as u128 / 1_000_000_000;
+        counts.clamp(MIN_COUNTS, u32::MAX as u128) as u32
+    };
+    match mode {
+        TimerMode::Periodic { hz } => {
+            write(base, LVT_TIMER, vector | LVT_PERIODIC);
+            write(base, INITIAL_COUNT, counts_after(1_000_000_000 / hz.max(1)));
+        }
+        TimerMode::OneShot { nanos } => {
+            write(base, LVT_TIMER, vector);
+            write(base, INITIAL_COUNT, counts_after(nanos));
+        }
+    }
+    take_over_from_pit();
+    Ok(())
+}
+
+/// Start the timer in place of the PIT, to interrupt once when the TSC reaches
+/// `tsc` (see `tsc_after`).
+pub fn start_tsc_deadline_timer(tsc: u64) -> Result<(), ApicError> {
+    let base = base()?;
+    if !supports_tsc_deadline() {
+        return Err(ApicError::NoTscDeadline);
+    }
+    write(base, LVT_TIMER, APIC_TIMER_VECTOR as u32 | LVT_TSC_DEADLINE);
+    // The SDM asks for a fence between switching modes & arming it.
+    unsafe {
+        core::arch::asm!("mfence", options(nostack));
+        Msr::new(IA32_TSC_DEADLINE).write(tsc);
+    }
+    take_over_from_pit();
+    Ok(())
+}
+
+fn take_over_from_pit() {
+    if !TIMER_RUNNING.swap(true, Ordering::Relaxed) {
+        interrupts::set_pit_enabled(false);
+    }
+}
+
+/// Stop the timer & give ticking back to the PIT.
+pub fn stop_timer() {
+    let Ok(base) = base() else {
+        return;
+    };
+    write(base, LVT_TIMER, LVT_MASKED | APIC_TIMER_VECTOR as u32);
+    write(base, INITIAL_COUNT, 0);
+    if supports_tsc_deadline() {
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) };
+    }
+    if TIMER_RUNNING.swap(false, Ordering::Relaxed) {
+        interrupts::set_pit_enabled(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn test_calibration() {
+        init().unwrap();
+        assert!(timer_frequency() > 0);
+        // Any CPU QEMU emulates runs its TSC faster than 100MHz.
+        assert!(tsc_frequency() > 100_000_000);
+    }
+
+    #[test_case]
+    fn test_timer_modes() {
+        hpet::init().unwrap();
+        init().unwrap();
+        time::check_timer_modes(start_timer, stop_timer);
+        assert!(!is_timer_running());
+        if supports_tsc_deadline() {
+            start_tsc_deadline_timer(tsc_after(1_000_000)).unwrap();
+            let start = interrupts::ticks();
+            while interrupts::ticks() == start {
+                x86_64::instructions::hlt();
+            }
+            stop_timer();
+        }
+    }
+}
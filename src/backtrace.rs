@@ -0,0 +1,161 @@
+//! backtrace.rs
+//! Stack backtraces by walking frame pointers. We build with frame pointers
+//! forced on (`frame-pointer` in `x86_64_os.json`), so every function starts with
+//! `push rbp; mov rbp, rsp`. That makes the stack a linked list of frames:
+//! - `[rbp]` is the caller's saved rbp (the next frame up)
+//! - `[rbp + 8]` is the return address into the caller
+//!
+//! We can't trust rbp when something has gone wrong, so every frame pointer is
+//! checked against the stacks we know about before we dereference it.
+
+use crate::{gdt, ksyms, println, serial_println};
+
+/// Where the bootloader puts our kernel stack, and its size in 4KiB pages.
+/// - Note: must match `[package.metadata.bootloader]` in `Cargo.toml`.
+const KERNEL_STACK_ADDRESS: u64 = 0xffff_ff80_0000_0000;
+const KERNEL_STACK_PAGES: u64 = 128;
+const PAGE_SIZE: u64 = 4096;
+/// Stop walking after this many frames (e.g. after runaway recursion).
+pub const MAX_FRAMES: usize = 32;
+
+/// Lowest & one-past-highest address of each stack we might find frames on.
+fn known_stacks() -> [(u64, u64); 2] {
+    let (double_fault_bottom, double_fault_top) = gdt::double_fault_stack();
+    [
+        // The first page is an unmapped guard page.
+        (
+            KERNEL_STACK_ADDRESS + PAGE_SIZE,
+            KERNEL_STACK_ADDRESS + KERNEL_STACK_PAGES * PAGE_SIZE,
+        ),
+        (double_fault_bottom.as_u64(), double_fault_top.as_u64()),
+    ]
+}
+
+/// Index into `known_stacks` of the stack holding a whole frame record at `rbp`.
+fn stack_containing(stacks: &[(u64, u64)], rbp: u64) -> Option<usize> {
+    stacks.iter().position(|&(bottom, top)| {
+        rbp >= bottom && rbp.checked_add(16).is_some_and(|end| end <= top)
+    })
+}
+
+/// Walk the frame pointer chain starting at `rbp`, calling `f` with each return
+/// address, innermost first. Stops at the first frame pointer that isn't
+/// aligned & inside a known stack. Returns the number of frames visited.
+pub fn walk(rbp: u64, f: impl FnMut(u64)) -> usize {
+    walk_stacks(&known_stacks(), rbp, f)
+}
+
+fn walk_stacks(stacks: &[(u64, u64)], mut rbp: u64, mut f: impl FnMut(u64)) -> usize {
+    let mut stack = match stack_containing(stacks, rbp) {
+        Some(stack) => stack,
+        None => return 0,
+    };
+    for depth in 0..MAX_FRAMES {
+        if !rbp.is_multiple_of(8) {
+            return depth;
+        }
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            return depth;
+        }
+        f(return_address);
+
+        // Frames only move up their stack (towards older frames)... unless we
+        // switched stacks, e.g. from an exception handler's IST stack back to
+        // the interrupted code.
+        match stack_containing(stacks, next) {
+            Some(next_stack) if next_stack != stack || next > rbp => {
+                stack = next_stack;
+                rbp = next;
+            }
+            _ => return depth + 1,
+        }
+    }
+    MAX_FRAMES
+}
+
+/// The frame pointer of whoever calls this.
+#[inline(always)]
+pub fn current_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    rbp
+}
+
+/// Print a backtrace of the caller to both serial and VGA.
+#[inline(never)]
+pub fn print() {
+    print_from(current_frame_pointer());
+}
+
+/// Print a backtrace starting from the frame at `rbp` to both serial and VGA,
+/// with symbol names if the kernel symbol table was filled in (see `ksyms`).
+pub fn print_from(rbp: u64) {
+    serial_println!("Backtrace:");
+    println!("Backtrace:");
+    let mut index = 0;
+    walk(rbp, |return_address| {
+        // The return address is just past the call, which might be the last
+        // instruction of its function, so look up the byte before it instead.
+        match ksyms::lookup(return_address - 1) {
+            Some((symbol, offset)) => {
+                serial_println!(
+                    "  #{:<2} {:#018x} {}+{:#x}",
+                    index,
+                    return_address,
+                    symbol,
+                    offset + 1
+                );
+                println!("  #{:<2} {}+{:#x}", index, symbol, offset + 1);
+            }
+            None => {
+                serial_println!("  #{:<2} {:#018x}", index, return_address);
+                println!("  #{:<2} {:#018x}", index, return_address);
+            }
+        }
+        index += 1;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_walk_current_stack() {
+        let mut frames = 0;
+        let visited = walk(current_frame_pointer(), |return_address| {
+            assert_ne!(return_address, 0);
+            frames += 1;
+        });
+        // At least our caller, the test runner & the test entry point
+        assert!(frames >= 3);
+        assert_eq!(frames, visited);
+    }
+
+    #[test_case]
+    fn test_walk_stops_outside_known_stacks() {
+        assert_eq!(walk(0, |_| panic!("walked a null frame pointer")), 0);
+        assert_eq!(
+            walk(0xdead_beef, |_| panic!("walked a bogus frame pointer")),
+            0
+        );
+    }
+
+    #[test_case]
+    fn test_walk_follows_fake_chain() {
+        // Three frame records, [saved rbp, return address], laid out like a stack.
+        let mut fake = [0u64; 6];
+        let base = fake.as_ptr() as u64;
+        // The last frame points back down the stack, which must end the walk.
+        fake.copy_from_slice(&[base + 16, 0x1111, base + 32, 0x2222, base, 0x3333]);
+        let stacks = [(base, base + 48)];
+
+        let mut seen = [0; 4];
+        let visited = walk_stacks(&stacks, base, |return_address| {
+            seen[return_address as usize / 0x1111 - 1] = return_address;
+        });
+        assert_eq!(visited, 3);
+        assert_eq!(seen, [0x1111, 0x2222, 0x3333, 0]);
+    }
+}
@@ -0,0 +1,170 @@
+//! bench.rs
+//! Micro-benchmarks that run on the test runner, timed in CPU cycles with the
+//! time stamp counter. Write one with the `#[kernel_bench]` attribute:
+//!
+//! ```ignore
+//! #[kernel_bench]
+//! fn bench_new_line(b: &mut Bencher) {
+//!     b.iter(|| WRITER.lock().new_line());
+//! }
+//! ```
+//!
+//! Like libtest, `cargo test` runs each benchmark's closure once as a smoke
+//! test, and only `cargo bench` (which `tools/runner.sh` tells the kernel about
+//! through fw_cfg) measures them, skipping the plain tests.
+
+use crate::{fw_cfg, start_test, test_report, Testable, DEFAULT_TEST_TIMEOUT_TICKS};
+use core::arch::asm;
+use core::hint::black_box;
+
+/// fw_cfg file that's present when running under `cargo bench`.
+const BENCH_MODE_FILE: &str = "opt/thompson_rust_os/bench";
+/// Untimed runs first, to warm up caches & branch predictors.
+const WARMUP_ITERATIONS: usize = 100;
+/// Timed runs per benchmark, one iteration each.
+pub const SAMPLES: usize = 1000;
+
+/// Whether we were started by `cargo bench`.
+pub fn is_bench_mode() -> bool {
+    fw_cfg::read_file(BENCH_MODE_FILE, &mut []).is_some()
+}
+
+/// Read the time stamp counter once all earlier instructions are done, & before
+/// any later ones start (`lfence` keeps `rdtsc` from being reordered).
+/// - Note: not `rdtscp`, which QEMU's default CPU doesn't have.
+#[inline(always)]
+fn cycles() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("lfence", "rdtsc", "lfence", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Cycles per iteration of a benchmark.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub min: u64,
+    pub median: u64,
+    pub mean: u64,
+    pub max: u64,
+    pub samples: usize,
+}
+
+impl Stats {
+    /// Sorts `samples` in place.
+    pub fn from_samples(samples: &mut [u64]) -> Stats {
+        if samples.is_empty() {
+            return Stats::default();
+        }
+        samples.sort_unstable();
+        let count = samples.len();
+        let median = if count.is_multiple_of(2) {
+            (samples[count / 2 - 1] + samples[count / 2]) / 2
+        } else {
+            samples[count / 2]
+        };
+        Stats {
+            min: samples[0],
+            median,
+            mean: samples.iter().sum::<u64>() / count as u64,
+            max: samples[count - 1],
+            samples: count,
+        }
+    }
+}
+
+/// Handed to each benchmark to time its closure with.
+pub struct Bencher {
+    /// Measure, or just run the closure once?
+    measure: bool,
+    stats: Option<Stats>,
+}
+
+impl Bencher {
+    /// Time `f`. Its result goes through `black_box`, so the compiler can't
+    /// optimize away the work.
+    pub fn iter<R>(&mut self, mut f: impl FnMut() -> R) {
+        if !self.measure {
+            black_box(f());
+            return;
+        }
+        for _ in 0..WARMUP_ITERATIONS {
+            black_box(f());
+        }
+        // The cost of reading the counter itself, so we can take it back out.
+        let overhead = (0..WARMUP_ITERATIONS)
+            .map(|_| {
+                let start = cycles();
+                cycles() - start
+            })
+            .min()
+            .unwrap_or(0);
+
+        let mut samples = [0u64; SAMPLES];
+        for sample in samples.iter_mut() {
+            let start = cycles();
+            black_box(f());
+            *sample = (cycles() - start).saturating_sub(overhead);
+        }
+        self.stats = Some(Stats::from_samples(&mut samples));
+    }
+}
+
+/// A benchmark made by the `#[kernel_bench]` attribute (see `macros/src/lib.rs`).
+pub struct KernelBench {
+    pub name: &'static str,
+    pub bench: fn(&mut Bencher),
+}
+
+impl Testable for KernelBench {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        start_test(self.name, DEFAULT_TEST_TIMEOUT_TICKS, false);
+        let mut bencher = Bencher {
+            measure: is_bench_mode(),
+            stats: None,
+        };
+        (self.bench)(&mut bencher);
+        match bencher.stats {
+            Some(stats) => test_report::bench_measured(self.name, &stats),
+            None => test_report::test_passed(self.name),
+        }
+    }
+
+    fn is_bench(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_stats_from_samples() {
+        let mut samples = [9, 1, 5, 3];
+        let stats = Stats::from_samples(&mut samples);
+        assert_eq!(
+            stats,
+            Stats {
+                min: 1,
+                median: 4,
+                mean: 4,
+                max: 9,
+                samples: 4
+            }
+        );
+        assert_eq!(Stats::from_samples(&mut [7]).median, 7);
+    }
+
+    #[test_case]
+    fn test_cycle_counter_advances() {
+        let start = cycles();
+        let end = cycles();
+        assert!(end > start);
+    }
+}
@@ -0,0 +1,102 @@
+//! coverage.rs
+//! Source-based code coverage for kernel tests, enabled by the `coverage`
+//! feature. The kernel has to be built with `-C instrument-coverage` for there
+//! to be anything to collect; `tools/coverage.sh` does the whole dance:
+//! builds & runs the tests, then turns what they dump into an lcov report.
+//!
+//! There's no file system to write a `.profraw` file to, so at the end of the
+//! test run we print it over serial as hex, between marker lines:
+//! ```text
+//! --- coverage profraw ---
+//! 6c70726f66726177...
+//! --- end coverage ---
+//! ```
+
+use crate::{serial_print, serial_println};
+use core::fmt;
+use minicov::{CoverageWriteError, CoverageWriter};
+
+pub const START_MARKER: &str = "--- coverage profraw ---";
+pub const END_MARKER: &str = "--- end coverage ---";
+/// Bytes of profile data per line (two hex digits each).
+const BYTES_PER_LINE: usize = 32;
+
+/// Hex encodes profile data into `W`, `BYTES_PER_LINE` to a line.
+struct HexWriter<W: fmt::Write> {
+    out: W,
+    column: usize,
+}
+
+impl<W: fmt::Write> HexWriter<W> {
+    fn new(out: W) -> Self {
+        HexWriter { out, column: 0 }
+    }
+
+    /// End the last (partial) line.
+    fn finish(mut self) -> fmt::Result {
+        if self.column != 0 {
+            self.out.write_char('\n')?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: fmt::Write> CoverageWriter for HexWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<(), CoverageWriteError> {
+        for byte in data {
+            write!(self.out, "{:02x}", byte).map_err(|_| CoverageWriteError)?;
+            self.column += 1;
+            if self.column == BYTES_PER_LINE {
+                self.out.write_char('\n').map_err(|_| CoverageWriteError)?;
+                self.column = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends what it's given to COM1.
+struct SerialOut;
+
+impl fmt::Write for SerialOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Print the coverage counters collected so far over serial.
+pub fn dump() {
+    serial_println!("{}", START_MARKER);
+    let mut writer = HexWriter::new(SerialOut);
+    // Not thread safe, but we only call this once the tests are all done.
+    let captured = unsafe { minicov::capture_coverage(&mut writer) };
+    if captured.is_err() || writer.finish().is_err() {
+        serial_println!("\nfailed to capture coverage");
+    }
+    serial_println!("{}", END_MARKER);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FmtBuffer;
+
+    #[test_case]
+    fn test_hex_writer_wraps_lines() {
+        let mut text = FmtBuffer::new();
+        let mut writer = HexWriter::new(&mut text);
+        let data: [u8; 33] = core::array::from_fn(|i| i as u8);
+        writer.write(&data[..10]).unwrap();
+        writer.write(&data[10..]).unwrap();
+        writer.write(&[0xff]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            text.as_str(),
+            concat!(
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n",
+                "20ff\n"
+            )
+        );
+    }
+}
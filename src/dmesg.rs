@@ -0,0 +1,208 @@
+//! dmesg.rs
+//! The kernel message buffer: every line logged through `logger` is kept here
+//! (by its `RING_BUFFER_SINK`), in a fixed-size static ring, so we have the
+//! whole boot history on hand when something goes wrong (named after Linux's
+//! `dmesg`). It deliberately doesn't depend on a heap or any other kernel
+//! subsystem, so it is still usable from a panic handler; see `replay_to_serial`.
+
+use crate::serial::{self, ComPort};
+use core::fmt;
+use spin::{Mutex, MutexGuard};
+
+/// Once this many bytes have been logged, the oldest lines get overwritten.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Fixed-size byte ring; once full, the oldest bytes are overwritten.
+struct Ring {
+    bytes: [u8; BUFFER_SIZE],
+    start: usize, // index of the oldest byte
+    len: usize,
+    wrapped: bool,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            bytes: [0; BUFFER_SIZE],
+            start: 0,
+            len: 0,
+            wrapped: false,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == BUFFER_SIZE {
+            self.bytes[self.start] = byte;
+            self.start = (self.start + 1) % BUFFER_SIZE;
+            self.wrapped = true;
+        } else {
+            self.bytes[(self.start + self.len) % BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Contents, oldest first, as two slices (the second is empty unless the
+    /// data wraps around the end of the array). After the ring has wrapped,
+    /// the oldest line is missing its beginning, so it's skipped.
+    fn lines(&self) -> (&[u8], &[u8]) {
+        let end = self.start + self.len;
+        let (mut first, mut second): (&[u8], &[u8]) = if end <= BUFFER_SIZE {
+            (&self.bytes[self.start..end], &[])
+        } else {
+            (&self.bytes[self.start..], &self.bytes[..end - BUFFER_SIZE])
+        };
+
+        if self.wrapped {
+            if let Some(i) = first.iter().position(|&b| b == b'\n') {
+                first = &first[i + 1..];
+            } else {
+                let skip = second
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .map_or(second.len(), |i| i + 1);
+                first = &[];
+                second = &second[skip..];
+            }
+        }
+        (first, second)
+    }
+
+    /// Copy as many complete lines as fit into `buf`, oldest first.
+    fn copy_to(&self, buf: &mut [u8]) -> usize {
+        let (first, second) = self.lines();
+        let from_first = first.len().min(buf.len());
+        buf[..from_first].copy_from_slice(&first[..from_first]);
+        let from_second = second.len().min(buf.len() - from_first);
+        buf[from_first..from_first + from_second].copy_from_slice(&second[..from_second]);
+        // Drop the line we ran out of room in the middle of.
+        buf[..from_first + from_second]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1)
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+static DMESG: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// If we panicked while writing to the buffer, its lock will never be released,
+/// so on the panic path we take it regardless.
+fn lock_for_panic() -> MutexGuard<'static, Ring> {
+    if let Some(ring) = DMESG.try_lock() {
+        return ring;
+    }
+    unsafe { DMESG.force_unlock() };
+    DMESG.lock()
+}
+
+/// Append a line to the buffer; a newline is added for you.
+pub fn record(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let _ = writeln!(DMESG.lock(), "{}", args);
+    });
+}
+
+/// Copy the buffered lines, oldest first, into `buf` and return how many bytes
+/// were copied. Lines that don't fit are left out; `len` tells you how big
+/// `buf` needs to be.
+pub fn read(buf: &mut [u8]) -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| DMESG.lock().copy_to(buf))
+}
+
+/// Bytes of complete lines currently held.
+pub fn len() -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let ring = DMESG.lock();
+        let (first, second) = ring.lines();
+        first.len() + second.len()
+    })
+}
+
+pub fn is_empty() -> bool {
+    len() == 0
+}
+
+pub fn clear() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| *DMESG.lock() = Ring::new());
+}
+
+/// Write the whole buffer to COM1. Meant for panic handlers, so it never waits
+/// on a lock the panicking code might have been holding.
+pub fn replay_to_serial() {
+    let ring = lock_for_panic();
+    let (first, second) = ring.lines();
+    if first.is_empty() && second.is_empty() {
+        return;
+    }
+    crate::serial_println!("--- dmesg ---");
+    if let Some(com1) = serial::handle(ComPort::Com1) {
+        com1.write_bytes(first);
+        com1.write_bytes(second);
+    }
+    crate::serial_println!("--- end dmesg ---");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn test_ring_skips_partial_line_after_wrapping() {
+        let mut ring = Ring::new();
+        writeln!(ring, "first").unwrap();
+        assert_eq!(ring.lines(), (&b"first\n"[..], &b""[..]));
+
+        // Overwrite most of the ring, cutting "first" in half.
+        for _ in 0..BUFFER_SIZE - 3 {
+            ring.push(b'x');
+        }
+        ring.push(b'\n');
+        writeln!(ring, "last").unwrap();
+
+        let (first, second) = ring.lines();
+        assert_eq!(first.len() + second.len(), 5);
+        assert_eq!(second, b"last\n");
+    }
+
+    #[test_case]
+    fn test_copy_to_truncates_to_buffer() {
+        let mut ring = Ring::new();
+        writeln!(ring, "one").unwrap();
+        writeln!(ring, "two").unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(ring.copy_to(&mut buf), 8);
+        assert_eq!(&buf[..8], b"one\ntwo\n");
+
+        let mut small = [0; 5];
+        assert_eq!(ring.copy_to(&mut small), 4);
+        assert_eq!(&small[..4], b"one\n");
+    }
+
+    #[test_case]
+    fn test_record_is_readable() {
+        record(format_args!("dmesg test {}", 42));
+
+        let mut buf = [0; BUFFER_SIZE];
+        let len = read(&mut buf);
+        assert!(buf[..len].ends_with(b"dmesg test 42\n"));
+    }
+}
@@ -0,0 +1,160 @@
+//! fuzz.rs
+//! Randomized-input tests that run under the normal test runner: feed a parser
+//! a few hundred pseudo-random inputs, and let any panic fail the test.
+//!
+//! ```ignore
+//! #[test_case]
+//! fn test_fuzz_parse_hex() {
+//!     fuzz::run(|input| {
+//!         parse_hex(input);
+//!     });
+//! }
+//! ```
+//!
+//! Every input is generated from its own seed, which is printed with the
+//! failure if the test panics or times out. To replay just that input:
+//! `FUZZ_SEED=0x... cargo test -- test_fuzz_parse_hex`. `FUZZ_ITERATIONS=N`
+//! tries more (or fewer) inputs per test. `tools/runner.sh` passes both in
+//! through fw_cfg.
+//! - Note: the parsers themselves live in the `parse` crate, so they can also be
+//!   fuzzed properly on the host with `cargo fuzz` (see `fuzz/`); this is the
+//!   cheap version that runs on every `cargo test`.
+
+use crate::{fw_cfg, set_test_timeout, DEFAULT_TEST_TIMEOUT_TICKS};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// fw_cfg file with one input's seed to replay, in decimal or `0x` hex.
+const SEED_FILE: &str = "opt/thompson_rust_os/fuzz-seed";
+/// fw_cfg file with the number of inputs to try per test.
+const ITERATIONS_FILE: &str = "opt/thompson_rust_os/fuzz-iterations";
+/// Where the per-input seeds come from, unless told otherwise. Fixed, so a
+/// run is the same every time.
+const BASE_SEED: u64 = 0x7468_6f6d_7073_6f6e;
+const DEFAULT_ITERATIONS: u64 = 256;
+/// Longest input we generate.
+pub const MAX_INPUT_LEN: usize = 512;
+
+/// Seed of the input being tested; 0 when we're not fuzzing.
+static CURRENT_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// xorshift64*: tiny, fast & plenty random enough to shake out parser bugs.
+/// Not for anything that needs to be unpredictable.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // An all zero state would only ever produce zeros.
+        Rng(if seed == 0 { BASE_SEED } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..bound` (a little biased, which doesn't matter here).
+    pub fn below(&mut self, bound: u64) -> u64 {
+        if bound == 0 {
+            0
+        } else {
+            self.next_u64() % bound
+        }
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// Read a number the runner handed us, e.g. `42` or `0x2a`.
+fn read_number(file: &str) -> Option<u64> {
+    let mut buf = [0; 24];
+    let len = fw_cfg::read_file(file, &mut buf)?;
+    let text = core::str::from_utf8(&buf[..len]).ok()?.trim();
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Call `test` with pseudo-random inputs of up to `MAX_INPUT_LEN` bytes. If
+/// the runner was given a `FUZZ_SEED`, only that one input is tried.
+pub fn run(test: impl Fn(&[u8])) {
+    let mut seeds = Rng::new(BASE_SEED);
+    let (replay, iterations) = match read_number(SEED_FILE) {
+        Some(seed) => (Some(seed), 1),
+        None => (
+            None,
+            read_number(ITERATIONS_FILE).unwrap_or(DEFAULT_ITERATIONS),
+        ),
+    };
+    let mut input = [0u8; MAX_INPUT_LEN];
+    for _ in 0..iterations {
+        let seed = replay.unwrap_or_else(|| seeds.next_u64());
+        CURRENT_SEED.store(seed, Ordering::SeqCst);
+        let mut rng = Rng::new(seed);
+        let len = rng.below(MAX_INPUT_LEN as u64 + 1) as usize;
+        rng.fill(&mut input[..len]);
+        // Lots of iterations shouldn't time the test out; one stuck input should.
+        set_test_timeout(DEFAULT_TEST_TIMEOUT_TICKS);
+        test(&input[..len]);
+    }
+    CURRENT_SEED.store(0, Ordering::SeqCst);
+}
+
+/// Forget the seed of a fuzz test that was cut short; `test_runner` calls this
+/// between tests.
+pub fn reset() {
+    CURRENT_SEED.store(0, Ordering::SeqCst);
+}
+
+/// Tacked onto failure messages: how to replay the input that was being
+/// tested, if any.
+pub struct FailingSeed;
+
+impl fmt::Display for FailingSeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match CURRENT_SEED.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            seed => write!(
+                f,
+                "\nfuzz: failing seed {:#x} (replay with FUZZ_SEED={:#x})",
+                seed, seed
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    #[test_case]
+    fn test_rng_is_deterministic() {
+        let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+        for _ in 0..16 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert_ne!(Rng::new(0).next_u64(), 0);
+        assert!(Rng::new(7).below(10) < 10);
+    }
+
+    #[test_case]
+    fn test_run_tries_inputs() {
+        let runs = Cell::new(0);
+        run(|input| {
+            assert!(input.len() <= MAX_INPUT_LEN);
+            assert_ne!(CURRENT_SEED.load(Ordering::SeqCst), 0);
+            runs.set(runs.get() + 1);
+        });
+        assert!(runs.get() > 0);
+        assert_eq!(CURRENT_SEED.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,104 @@
+//! fw_cfg.rs
+//! Reads QEMU's [firmware configuration device](https://www.qemu.org/docs/master/specs/fw_cfg.html),
+//! which is how the host hands us named blobs, e.g. from
+//! `qemu-system-x86_64 -fw_cfg name=opt/foo,string=bar`.
+//!
+//! On x86 it's two I/O ports: write a 16 bit item selector to one, then read
+//! the item's bytes out of the other, one at a time. All multi-byte fields in
+//! the file directory are big endian.
+
+use x86_64::instructions::port::Port;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+/// Item holding the "QEMU" signature.
+const SIGNATURE_ITEM: u16 = 0x0000;
+/// Item holding the file directory: a u32 count, then `FileEntry`s.
+const FILE_DIR_ITEM: u16 = 0x0019;
+/// Bytes in each directory entry: u32 size, u16 select, u16 reserved, 56 byte name.
+const FILE_ENTRY_SIZE: usize = 64;
+const FILE_NAME_SIZE: usize = 56;
+
+/// Start reading `item` from its first byte.
+fn select(item: u16) {
+    let mut port: Port<u16> = Port::new(SELECTOR_PORT);
+    unsafe { port.write(item) };
+}
+
+/// Read the next bytes of the selected item.
+fn read(buf: &mut [u8]) {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    for byte in buf {
+        *byte = unsafe { port.read() };
+    }
+}
+
+/// Whether we're running under QEMU with a fw_cfg device.
+pub fn is_present() -> bool {
+    let mut signature = [0; 4];
+    select(SIGNATURE_ITEM);
+    read(&mut signature);
+    &signature == b"QEMU"
+}
+
+/// Find a file's item selector & size in the directory.
+fn find_file(name: &str) -> Option<(u16, usize)> {
+    if !is_present() || name.len() >= FILE_NAME_SIZE {
+        return None;
+    }
+    let mut count = [0; 4];
+    select(FILE_DIR_ITEM);
+    read(&mut count);
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut entry = [0; FILE_ENTRY_SIZE];
+        read(&mut entry);
+        // Names are NUL padded.
+        let entry_name = &entry[8..];
+        if &entry_name[..name.len()] == name.as_bytes() && entry_name[name.len()] == 0 {
+            let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let item = u16::from_be_bytes([entry[4], entry[5]]);
+            return Some((item, size as usize));
+        }
+    }
+    None
+}
+
+/// Copy the file called `name` into `buf`, returning its length, or `None` if
+/// there's no such file. Files longer than `buf` are cut short.
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    let (item, size) = find_file(name)?;
+    let len = size.min(buf.len());
+    select(item);
+    read(&mut buf[..len]);
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_fw_cfg_present() {
+        // We only ever run tests under QEMU.
+        assert!(is_present());
+    }
+
+    #[test_case]
+    fn test_missing_file() {
+        let mut buf = [0; 16];
+        assert_eq!(
+            read_file("opt/thompson_rust_os/no-such-file", &mut buf),
+            None
+        );
+    }
+
+    #[test_case]
+    fn test_read_known_file() {
+        // QEMU's PC machines always describe their memory map here, in
+        // 20 byte entries.
+        let mut buf = [0; 64];
+        let len = read_file("etc/e820", &mut buf).unwrap();
+        assert!(len >= 20);
+    }
+}
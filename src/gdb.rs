@@ -0,0 +1,668 @@
+//! gdb.rs
+//! A stub for the [GDB Remote Serial Protocol](https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html)
+//! so a host `gdb` can debug the kernel through a serial port, rather than only
+//! through QEMU's own `-s` gdbserver.
+//!
+//! Once `init` attaches the stub to a port, the kernel stops and talks to GDB
+//! whenever:
+//! - an `int3` is hit (one of GDB's software breakpoints, or `gdb::breakpoint()`)
+//! - a single step completes (#DB exception, via the trap flag)
+//! - GDB sends us something while we're running (checked on every timer tick),
+//!   which is how the initial `target remote` and Ctrl-C work
+//!
+//! While stopped we poll the UART with interrupts off, so nothing else runs.
+//! - Note: we can only see the registers the CPU saves in the `InterruptStackFrame`
+//!   (rip, rflags, rsp, cs, ss). The `x86-interrupt` calling convention hides the
+//!   general purpose registers from us, so they are reported as unavailable.
+
+use crate::serial::{self, ComPort, SerialError, SerialHandle};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use thompson_rust_os_parse::gdb::{
+    checksum, decode_le, parse_addr_len, parse_hex, split_once, Decoded, PacketDecoder,
+};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Largest packet we accept or send (advertised to GDB through `qSupported`).
+const MAX_PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+/// Trap flag in RFLAGS: the CPU raises #DB after executing one instruction.
+const TRAP_FLAG: u64 = 1 << 8;
+/// What GDB sends (outside of a packet) when the user hits Ctrl-C.
+const INTERRUPT_REQUEST: u8 = 0x03;
+
+// GDB's register numbers for x86_64 (see gdb/features/i386/64bit-core.xml)
+const REG_RSP: usize = 7;
+const REG_RIP: usize = 16;
+const REG_EFLAGS: usize = 17;
+const REG_CS: usize = 18;
+const REG_SS: usize = 19;
+const REG_GS: usize = 23;
+
+/// Unix signal numbers, which is how GDB wants to be told why we stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal {
+    Interrupt = 2,
+    Trap = 5,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+enum Resume {
+    Continue,
+    Step,
+    /// Continue, but GDB isn't waiting to hear from us anymore.
+    Detach,
+}
+
+struct Stub {
+    serial: SerialHandle,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// Set once we've told GDB we're running, so it's waiting on a stop reply.
+    running: bool,
+}
+
+static STUB: Mutex<Option<Stub>> = Mutex::new(None);
+/// Lets the timer interrupt check for us cheaply, without taking `STUB`'s lock.
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Listen for GDB on `com`. The port is switched to polled mode, so it can't be
+/// used for anything else.
+pub fn init(com: ComPort) -> Result<(), SerialError> {
+    use x86_64::instructions::interrupts;
+
+    let serial = serial::handle(com).ok_or(SerialError::NotPresent(com))?;
+    serial.set_polled(true);
+    interrupts::without_interrupts(|| {
+        *STUB.lock() = Some(Stub {
+            serial,
+            breakpoints: [None; MAX_BREAKPOINTS],
+            running: false,
+        });
+    });
+    ATTACHED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::SeqCst)
+}
+
+/// Stop here and hand control to GDB (if attached).
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Called from the breakpoint (`int3`) handler. Returns false if the stub isn't
+/// attached, so the caller can handle the exception as usual.
+pub fn handle_breakpoint(frame: &mut InterruptStackFrame) -> bool {
+    with_stub(|stub| {
+        // `int3` traps *after* executing, so rip is one past the breakpoint. If
+        // it's one of GDB's, rewind so the original instruction runs on resume
+        // (GDB removes its breakpoints while stopped).
+        let addr = frame.instruction_pointer.as_u64() - 1;
+        if stub.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+            unsafe {
+                frame
+                    .as_mut()
+                    .update(|f| f.instruction_pointer = VirtAddr::new(addr))
+            };
+        }
+        stub.session(frame, Signal::Trap);
+    })
+}
+
+/// Called from the debug (#DB) exception handler, raised after a single step.
+pub fn handle_debug(frame: &mut InterruptStackFrame) -> bool {
+    with_stub(|stub| {
+        unsafe { frame.as_mut().update(|f| f.cpu_flags &= !TRAP_FLAG) };
+        stub.session(frame, Signal::Trap);
+    })
+}
+
+/// Called from the timer interrupt; stops the kernel if GDB has sent us
+/// anything (a Ctrl-C, or the first packets of a new connection).
+pub fn poll(frame: &mut InterruptStackFrame) {
+    if !is_attached() {
+        return;
+    }
+    with_stub(|stub| {
+        match stub.serial.try_receive() {
+            // Start of a packet; `session` will wait for the rest of it.
+            Some(b'$') => stub.session_with_pending_packet(frame),
+            Some(INTERRUPT_REQUEST) => stub.session(frame, Signal::Interrupt),
+            // Stray acks etc. don't mean anything while we're running.
+            _ => {}
+        }
+    });
+}
+
+fn with_stub(f: impl FnOnce(&mut Stub)) -> bool {
+    if !is_attached() {
+        return false;
+    }
+    // We're in an exception handler; if the lock is held we were stopped inside
+    // the stub itself, which we can't debug.
+    match STUB.try_lock() {
+        Some(mut stub) => match stub.as_mut() {
+            Some(stub) => {
+                f(stub);
+                true
+            }
+            None => false,
+        },
+        None => false,
+    }
+}
+
+impl Stub {
+    fn session_with_pending_packet(&mut self, frame: &mut InterruptStackFrame) {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let stopped = Signal::Interrupt;
+        // `poll` already took the packet's `$`.
+        if let Some(len) = self.finish_packet(PacketDecoder::after_start(), &mut buf) {
+            if let Some(resume) = self.handle_packet(frame, &buf[..len], stopped) {
+                self.resume(frame, resume);
+                return;
+            }
+        }
+        self.session(frame, stopped);
+    }
+
+    /// Talk to GDB until it tells us to continue or step.
+    fn session(&mut self, frame: &mut InterruptStackFrame, signal: Signal) {
+        if self.running {
+            // GDB is waiting to hear why we stopped.
+            self.send_stop_reply(signal);
+        }
+        let mut buf = [0; MAX_PACKET_SIZE];
+        loop {
+            let len = self.read_packet(&mut buf);
+            if let Some(resume) = self.handle_packet(frame, &buf[..len], signal) {
+                self.resume(frame, resume);
+                return;
+            }
+        }
+    }
+
+    fn resume(&mut self, frame: &mut InterruptStackFrame, resume: Resume) {
+        let flags = match resume {
+            Resume::Continue | Resume::Detach => frame.cpu_flags & !TRAP_FLAG,
+            Resume::Step => frame.cpu_flags | TRAP_FLAG,
+        };
+        unsafe { frame.as_mut().update(|f| f.cpu_flags = flags) };
+        self.running = !matches!(resume, Resume::Detach);
+    }
+
+    /// Handle one packet, returning how to resume if it was a continue or step.
+    fn handle_packet(
+        &mut self,
+        frame: &mut InterruptStackFrame,
+        packet: &[u8],
+        signal: Signal,
+    ) -> Option<Resume> {
+        let mut response = Response::new();
+        let (&command, args) = match packet.split_first() {
+            Some(split) => split,
+            None => {
+                self.send_packet(&response);
+                return None;
+            }
+        };
+
+        match command {
+            b'?' => {
+                self.send_stop_reply(signal);
+                return None;
+            }
+            b'g' => write_registers(frame, &mut response),
+            b'G' => {
+                if set_registers(frame, args) {
+                    response.push_str("OK");
+                } else {
+                    response.push_str("E01");
+                }
+            }
+            b'p' => match parse_hex(args).and_then(|reg| register(frame, reg as usize)) {
+                Some((value, size)) => response.push_register(value, size),
+                None => response.push_str("E01"),
+            },
+            b'P' => match split_once(args, b'=') {
+                Some((reg, value)) if set_register(frame, reg, value) => response.push_str("OK"),
+                _ => response.push_str("E01"),
+            },
+            b'm' => match parse_addr_len(args) {
+                // Two hex digits per byte of memory
+                Some((addr, len)) => {
+                    if !read_memory(addr, len.min(MAX_PACKET_SIZE / 2), &mut response) {
+                        response.push_str("E14");
+                    }
+                }
+                None => response.push_str("E01"),
+            },
+            b'M' => match split_once(args, b':') {
+                Some((addr_len, data)) => match parse_addr_len(addr_len) {
+                    Some((addr, len)) if write_memory(addr, len, data) => response.push_str("OK"),
+                    _ => response.push_str("E14"),
+                },
+                None => response.push_str("E01"),
+            },
+            // Software breakpoints only; `Z1` (hardware) etc. stay unsupported.
+            b'Z' | b'z' if args.starts_with(b"0,") => {
+                let ok = match split_once(&args[2..], b',').and_then(|(addr, _)| parse_hex(addr)) {
+                    Some(addr) if command == b'Z' => self.insert_breakpoint(addr),
+                    Some(addr) => self.remove_breakpoint(addr),
+                    None => false,
+                };
+                response.push_str(if ok { "OK" } else { "E01" });
+            }
+            b'c' | b's' => {
+                // Optional address to resume at
+                if let Some(addr) = parse_hex(args).and_then(|addr| VirtAddr::try_new(addr).ok()) {
+                    unsafe { frame.as_mut().update(|f| f.instruction_pointer = addr) };
+                }
+                return Some(if command == b'c' {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                });
+            }
+            // Keep listening afterwards, so GDB can reconnect later.
+            b'D' => {
+                self.remove_all_breakpoints();
+                response.push_str("OK");
+                self.send_packet(&response);
+                return Some(Resume::Detach);
+            }
+            b'k' => {
+                self.remove_all_breakpoints();
+                // Only does anything under QEMU; otherwise just stop for good.
+                crate::exit_qemu(crate::QemuExitCode::Success);
+                crate::hlt_loop();
+            }
+            b'q' if args.starts_with(b"Supported") => {
+                use core::fmt::Write;
+                let _ = write!(response, "PacketSize={:x}", MAX_PACKET_SIZE);
+            }
+            b'q' if args == b"Attached" => response.push_str("1"),
+            // We only have one thread, so any thread selection is fine.
+            b'H' => response.push_str("OK"),
+            // An empty response tells GDB we don't support the packet.
+            _ => {}
+        }
+        self.send_packet(&response);
+        None
+    }
+
+    fn send_stop_reply(&mut self, signal: Signal) {
+        let mut response = Response::new();
+        response.push(b'S');
+        response.push_hex_byte(signal as u8);
+        self.send_packet(&response);
+        self.running = false;
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> bool {
+        if self.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+            return true;
+        }
+        let slot = match self.breakpoints.iter_mut().find(|bp| bp.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let original = match read_byte(addr) {
+            Some(byte) => byte,
+            None => return false,
+        };
+        if !write_byte(addr, INT3) {
+            return false;
+        }
+        *slot = Some(Breakpoint { addr, original });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(bp) = slot {
+                if bp.addr == addr {
+                    write_byte(bp.addr, bp.original);
+                    *slot = None;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(bp) = slot.take() {
+                write_byte(bp.addr, bp.original);
+            }
+        }
+    }
+
+    fn receive(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.serial.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait for a complete, valid packet and acknowledge it.
+    fn read_packet(&self, buf: &mut [u8; MAX_PACKET_SIZE]) -> usize {
+        loop {
+            if let Some(len) = self.finish_packet(PacketDecoder::new(), buf) {
+                return len;
+            }
+        }
+    }
+
+    /// Feed `decoder` until it has a whole packet, then ack or nack it
+    /// depending on whether the checksum matched.
+    fn finish_packet(
+        &self,
+        mut decoder: PacketDecoder,
+        buf: &mut [u8; MAX_PACKET_SIZE],
+    ) -> Option<usize> {
+        loop {
+            match decoder.push(self.receive(), buf) {
+                Some(Decoded::Packet(len)) => {
+                    self.serial.write_bytes(b"+");
+                    return Some(len);
+                }
+                Some(Decoded::Corrupt) => {
+                    self.serial.write_bytes(b"-");
+                    return None;
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Send `$<data>#<checksum>`, resending until GDB acks it.
+    fn send_packet(&self, response: &Response) {
+        let data = response.as_bytes();
+        let sum = checksum(data);
+        let trailer = [
+            b'#',
+            HEX_DIGITS[(sum >> 4) as usize],
+            HEX_DIGITS[(sum & 0xf) as usize],
+        ];
+        loop {
+            self.serial.write_bytes(b"$");
+            self.serial.write_bytes(data);
+            self.serial.write_bytes(&trailer);
+            if self.receive() != b'-' {
+                return;
+            }
+        }
+    }
+}
+
+/// GDB's value & size in bytes for register number `reg`, if we know its value.
+/// Registers we know exist but can't see are `Some((None, size))`.
+fn register(frame: &InterruptStackFrame, reg: usize) -> Option<(Option<u64>, usize)> {
+    let value = match reg {
+        REG_RSP => Some(frame.stack_pointer.as_u64()),
+        REG_RIP => Some(frame.instruction_pointer.as_u64()),
+        REG_EFLAGS => Some(frame.cpu_flags),
+        REG_CS => Some(frame.code_segment),
+        REG_SS => Some(frame.stack_segment),
+        0..=REG_GS => None,
+        _ => return None,
+    };
+    // The 64 bit registers come first, everything from eflags on is 32 bit.
+    let size = if reg < REG_EFLAGS { 8 } else { 4 };
+    Some((value, size))
+}
+
+/// The `g` packet: every core register, in GDB's order, as little-endian hex.
+fn write_registers(frame: &InterruptStackFrame, response: &mut Response) {
+    for reg in 0..=REG_GS {
+        if let Some((value, size)) = register(frame, reg) {
+            response.push_register(value, size);
+        }
+    }
+}
+
+/// The `G` packet: only rsp, rip & rflags can be changed; the rest is ignored.
+fn set_registers(frame: &mut InterruptStackFrame, hex: &[u8]) -> bool {
+    let mut offset = 0;
+    for reg in 0..=REG_GS {
+        let size = match register(frame, reg) {
+            Some((_, size)) => size,
+            None => return false,
+        };
+        let field = match hex.get(offset..offset + size * 2) {
+            Some(field) => field,
+            None => return false,
+        };
+        // "xx" means GDB doesn't know the value either; leave it.
+        if let Some(value) = decode_le(field) {
+            set_frame_register(frame, reg, value);
+        }
+        offset += size * 2;
+    }
+    true
+}
+
+/// The `P` packet: `reg` is a hex register number, `value` little-endian hex.
+fn set_register(frame: &mut InterruptStackFrame, reg: &[u8], value: &[u8]) -> bool {
+    match (parse_hex(reg), decode_le(value)) {
+        (Some(reg), Some(value)) => set_frame_register(frame, reg as usize, value),
+        _ => false,
+    }
+}
+
+fn set_frame_register(frame: &mut InterruptStackFrame, reg: usize, value: u64) -> bool {
+    let addr = VirtAddr::try_new(value);
+    unsafe {
+        match (reg, addr) {
+            (REG_RSP, Ok(addr)) => frame.as_mut().update(|f| f.stack_pointer = addr),
+            (REG_RIP, Ok(addr)) => frame.as_mut().update(|f| f.instruction_pointer = addr),
+            (REG_EFLAGS, _) => frame.as_mut().update(|f| f.cpu_flags = value),
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// `None` if `addr` isn't a canonical, mapped address.
+fn read_byte(addr: u64) -> Option<u8> {
+    let addr = VirtAddr::try_new(addr).ok()?;
+    crate::memory::translate_addr(addr)?;
+    Some(unsafe { core::ptr::read_volatile(addr.as_ptr::<u8>()) })
+}
+
+/// Writes through read-only mappings too (e.g. setting breakpoints in `.text`)
+/// by briefly clearing CR0's write protect bit.
+fn write_byte(addr: u64, value: u8) -> bool {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    let addr = match VirtAddr::try_new(addr) {
+        Ok(addr) if crate::memory::translate_addr(addr).is_some() => addr,
+        _ => return false,
+    };
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        core::ptr::write_volatile(addr.as_mut_ptr::<u8>(), value);
+        Cr0::write(cr0);
+    }
+    true
+}
+
+/// Hex dump of up to `len` bytes; stops early at unmapped memory, and fails
+/// if not even the first byte could be read.
+fn read_memory(addr: u64, len: usize, response: &mut Response) -> bool {
+    for i in 0..len as u64 {
+        match read_byte(addr.wrapping_add(i)) {
+            Some(byte) => response.push_hex_byte(byte),
+            None => return i > 0,
+        }
+    }
+    true
+}
+
+fn write_memory(addr: u64, len: usize, hex: &[u8]) -> bool {
+    if hex.len() != len * 2 {
+        return false;
+    }
+    for (i, pair) in hex.chunks_exact(2).enumerate() {
+        let ok =
+            parse_hex(pair).is_some_and(|byte| write_byte(addr.wrapping_add(i as u64), byte as u8));
+        if !ok {
+            return false;
+        }
+    }
+    true
+}
+
+/*
+Packet encoding helpers. Everything in the protocol is ASCII: numbers are hex,
+memory & register contents are hex pairs, and packets end with a checksum that
+is the sum of their bytes modulo 256.
+- Note: decoding lives in the `parse` crate, so it can be fuzzed on the host
+  (see `fuzz/`); we only encode here.
+*/
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Reply under construction; silently truncated at `MAX_PACKET_SIZE`.
+struct Response {
+    buf: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    fn new() -> Response {
+        Response {
+            buf: [0; MAX_PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex_byte(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xf) as usize]);
+    }
+
+    /// Register values are sent in target (little-endian) byte order; unknown
+    /// values are sent as "xx" for each byte.
+    fn push_register(&mut self, value: Option<u64>, size: usize) {
+        for i in 0..size {
+            match value {
+                Some(value) => self.push_hex_byte((value >> (i * 8)) as u8),
+                None => self.push_str("xx"),
+            }
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl core::fmt::Write for Response {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_response_encodes_registers() {
+        let mut response = Response::new();
+        response.push_register(Some(0x0102), 4);
+        response.push_register(None, 2);
+        assert_eq!(response.as_bytes(), b"02010000xxxx");
+    }
+
+    #[test_case]
+    fn test_read_memory_stops_at_unmapped() {
+        let value: u16 = 0xbeef;
+        let mut response = Response::new();
+        assert!(read_memory(&value as *const u16 as u64, 2, &mut response));
+        assert_eq!(response.as_bytes(), b"efbe");
+
+        let mut response = Response::new();
+        assert!(!read_memory(0, 1, &mut response));
+    }
+
+    #[test_case]
+    fn test_fuzz_packet_decoder() {
+        crate::fuzz::run(|input| {
+            // Line noise mustn't trip it up.
+            let mut decoder = PacketDecoder::new();
+            let mut buf = [0u8; 64];
+            for &byte in input {
+                if let Some(Decoded::Packet(len)) = decoder.push(byte, &mut buf) {
+                    assert!(len <= buf.len());
+                }
+            }
+            // And the input as a packet's data should come back out intact.
+            let data = &input[..input.len().min(buf.len())];
+            let sum = checksum(data);
+            let trailer = [
+                b'#',
+                HEX_DIGITS[(sum >> 4) as usize],
+                HEX_DIGITS[(sum & 0xf) as usize],
+            ];
+            let mut decoder = PacketDecoder::new();
+            let mut decoded = None;
+            for &byte in b"+$".iter().chain(data).chain(&trailer) {
+                decoded = decoder.push(byte, &mut buf);
+            }
+            if data.contains(&b'#') {
+                // Ends the packet early; the checksum digits are wrong.
+                return;
+            }
+            assert_eq!(decoded, Some(Decoded::Packet(data.len())));
+            assert_eq!(&buf[..data.len()], data);
+        });
+    }
+
+    #[test_case]
+    fn test_fuzz_hex_parsers() {
+        crate::fuzz::run(|input| {
+            parse_hex(input);
+            decode_le(input);
+            parse_addr_len(input);
+            // Round trip the input's first bytes as a number.
+            let mut bytes = [0u8; 8];
+            let len = input.len().min(8);
+            bytes[..len].copy_from_slice(&input[..len]);
+            let value = u64::from_le_bytes(bytes);
+            let mut response = Response::new();
+            response.push_register(Some(value), 8);
+            assert_eq!(decode_le(response.as_bytes()), Some(value));
+        });
+    }
+}
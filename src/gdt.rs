@@ -21,22 +21,26 @@ struct GlobalDescriptorWrapper {
     selectors: Selectors,
 }
 
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+// Need to use this b/c we haven't implemeneted memory management.
+// If this was not mut, bootloader would map to a read-only page.
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// Lowest & one-past-highest address of the double fault handler's stack.
+pub fn double_fault_stack() -> (VirtAddr, VirtAddr) {
+    // Through a raw pointer, so we never make a reference to the static mut.
+    let stack_start = VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK);
+    (stack_start, stack_start + DOUBLE_FAULT_STACK_SIZE)
+}
+
 lazy_static! {
     // Build out our interrupt stack table with a double fault stack impl.
     // Note: no guard page in this stack, so can't do anything stack
     // intensive in our double fault handler.
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // Need to use this b/c we haven't implemeneted memory management.
-            // If this was not mut, bootloader would map to a read-only page.
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // This needs to be unsafe b/c of the static mut access.
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
+        // Stacks grow down, so the stack pointer starts at the top.
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack().1;
         tss
     };
 }
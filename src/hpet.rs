@@ -0,0 +1,223 @@
+//! hpet.rs
+//! Driver for the [High Precision Event Timer](https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf):
+//! a free running counter (100MHz on QEMU) that's one memory read away, unlike
+//! the PIT's ~18Hz ticks, plus a few comparators that can raise interrupts.
+//!
+//! We find it through the ACPI `HPET` table. Its registers are memory mapped:
+//! - 0x000: capabilities; the top 32 bits are the counter's period in femtoseconds
+//! - 0x010: configuration; enable the counter & "legacy replacement" routing
+//! - 0x0f0: the main counter
+//! - 0x100 + 0x20 * n: timer n's configuration, then its comparator at + 0x08
+//!
+//! With legacy replacement on, timer 0 raises IRQ 0 in place of the PIT (and
+//! timer 1 would raise IRQ 8 in place of the RTC), so it lands in the same
+//! IRQ 0 handler & nothing else needs to know.
+//! - Note: requires `acpi::init` to have been called.
+
+use crate::time::TimerMode;
+use crate::{acpi, memory};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+/// Bytes of registers, for a block with the full 32 timers.
+const REGISTERS_SIZE: u64 = 0x400;
+
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const TIMER_0_CONFIGURATION: usize = 0x100;
+const TIMER_0_COMPARATOR: usize = 0x108;
+
+/// Capability bits.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const LEG_RT_CAP: u64 = 1 << 15;
+/// Configuration bits.
+const ENABLE_CNF: u64 = 1 << 0;
+const LEG_RT_CNF: u64 = 1 << 1;
+/// Timer configuration bits.
+const TN_INT_TYPE_CNF: u64 = 1 << 1;
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+
+/// Least a one-shot is armed ahead of the counter, so the comparator write
+/// usually lands before the counter gets there (1µs at QEMU's 100MHz).
+const MIN_ONE_SHOT_TICKS: u64 = 100;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+const FEMTOS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// Virtual address of the registers; 0 until `init` finds them.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Femtoseconds per counter tick.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// No ACPI `HPET` table.
+    NotPresent,
+    /// The table says the registers are somewhere other than in memory.
+    NotMemoryMapped,
+    /// The registers aren't in the bootloader's physical memory mapping.
+    Unmapped(u64),
+    /// We only handle 64 bit counters, which never wrap in practice.
+    Counter32Bit,
+    /// Timer 0 can't stand in for the PIT.
+    NoLegacyReplacement,
+    /// Timer 0 can't do periodic interrupts.
+    NoPeriodicMode,
+    /// `init` hasn't found an HPET yet.
+    NotInitialized,
+}
+
+fn base() -> Result<u64, HpetError> {
+    match BASE.load(Ordering::Relaxed) {
+        0 => Err(HpetError::NotInitialized),
+        base => Ok(base),
+    }
+}
+
+fn read(base: u64, register: usize) -> u64 {
+    unsafe { ((base + register as u64) as *const u64).read_volatile() }
+}
+
+fn write(base: u64, register: usize, value: u64) {
+    unsafe { ((base + register as u64) as *mut u64).write_volatile(value) }
+}
+
+/// Find the HPET & start its counter running.
+pub fn init() -> Result<(), HpetError> {
+    let table = acpi::tables()
+        .and_then(|tables| tables.hpet)
+        .ok_or(HpetError::NotPresent)?;
+    let address = table.base_address;
+    if address.space != acpi::AddressSpace::SystemMemory {
+        return Err(HpetError::NotMemoryMapped);
+    }
+    let phys =
+        PhysAddr::try_new(address.address).map_err(|_| HpetError::Unmapped(address.address))?;
+    let base = memory::map_mmio(phys, REGISTERS_SIZE)
+        .ok_or(HpetError::Unmapped(address.address))?
+        .as_u64();
+
+    let capabilities = read(base, CAPABILITIES);
+    if capabilities & COUNT_SIZE_CAP == 0 {
+        return Err(HpetError::Counter32Bit);
+    }
+    PERIOD_FS.store(capabilities >> 32, Ordering::Relaxed);
+    write(base, CONFIGURATION, read(base, CONFIGURATION) | ENABLE_CNF);
+    BASE.store(base, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn is_present() -> bool {
+    base().is_ok()
+}
+
+/// Raw counter value; 0 if there's no HPET.
+pub fn counter() -> u64 {
+    base().map_or(0, |base| read(base, MAIN_COUNTER))
+}
+
+/// Counter ticks per second.
+pub fn frequency() -> u64 {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => 0,
+        period => FEMTOS_PER_SECOND / period,
+    }
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * PERIOD_FS.load(Ordering::Relaxed) as u128 / FEMTOS_PER_NANO) as u64
+}
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => 0,
+        period => (nanos as u128 * FEMTOS_PER_NANO / period as u128) as u64,
+    }
+}
+
+/// Nanoseconds since the HPET was enabled (by us, or the firmware); never
+/// goes backwards. 0 if there's no HPET.
+pub fn nanos() -> u64 {
+    ticks_to_nanos(counter())
+}
+
+/// Have timer 0 raise the timer interrupt (IRQ 0) instead of the PIT.
+pub fn start_timer(mode: TimerMode) -> Result<(), HpetError> {
+    let base = base()?;
+    if read(base, CAPABILITIES) & LEG_RT_CAP == 0 {
+        return Err(HpetError::NoLegacyReplacement);
+    }
+    let config = read(base, TIMER_0_CONFIGURATION);
+    // Edge triggered, which is what the PIC expects.
+    let config = config & !(TN_INT_TYPE_CNF | TN_TYPE_CNF | TN_VAL_SET_CNF);
+    match mode {
+        TimerMode::Periodic { hz } => {
+            if config & TN_PER_INT_CAP == 0 {
+                return Err(HpetError::NoPeriodicMode);
+            }
+            let period = (frequency() / hz.max(1)).max(1);
+            write(
+                base,
+                TIMER_0_CONFIGURATION,
+                config | TN_INT_ENB_CNF | TN_TYPE_CNF | TN_VAL_SET_CNF,
+            );
+            // With `Tn_VAL_SET_CNF`, the first write sets when the first
+            // interrupt comes, & the second how far apart the rest are.
+            write(base, TIMER_0_COMPARATOR, counter() + period);
+            write(base, TIMER_0_COMPARATOR, period);
+        }
+        TimerMode::OneShot { nanos } => {
+            write(base, TIMER_0_CONFIGURATION, config | TN_INT_ENB_CNF);
+            // The comparator only fires when the counter equals it, so if the
+            // counter's already past it once the write lands, the interrupt
+            // wouldn't come until the counter wraps. Re-arm further out then.
+            let mut ticks = nanos_to_ticks(nanos).max(MIN_ONE_SHOT_TICKS);
+            loop {
+                let deadline = counter() + ticks;
+                write(base, TIMER_0_COMPARATOR, deadline);
+                if read(base, MAIN_COUNTER) < deadline {
+                    break;
+                }
+                ticks *= 2;
+            }
+        }
+    }
+    write(base, CONFIGURATION, read(base, CONFIGURATION) | LEG_RT_CNF);
+    Ok(())
+}
+
+/// Stop timer 0's interrupts & hand IRQ 0 back to the PIT.
+pub fn stop_timer() {
+    let Ok(base) = base() else {
+        return;
+    };
+    write(base, CONFIGURATION, read(base, CONFIGURATION) & !LEG_RT_CNF);
+    let config = read(base, TIMER_0_CONFIGURATION);
+    write(base, TIMER_0_CONFIGURATION, config & !TN_INT_ENB_CNF);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time;
+
+    #[test_case]
+    fn test_counter_is_monotonic() {
+        init().unwrap();
+        // QEMU's runs at 100MHz.
+        assert_eq!(frequency(), 100_000_000);
+        let start = nanos();
+        while nanos() == start {}
+        assert!(nanos() > start);
+    }
+
+    #[test_case]
+    fn test_timer_replaces_pit() {
+        init().unwrap();
+        time::check_timer_modes(start_timer, stop_timer);
+    }
+}
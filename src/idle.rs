@@ -0,0 +1,159 @@
+//! idle.rs
+//! What a CPU does when there's nothing to do: halt until an interrupt. With
+//! the local APIC timer ticking (see `apic.rs`), we go "tickless" first: swap
+//! the periodic tick for a one-shot timer that fires at the next kernel timer
+//! (see `timer.rs`; `time::sleep` waits on one too), so an idle CPU isn't
+//! woken up `TIMER_HZ` times a second for nothing. On the way back,
+//! `interrupts::ticks()` is caught up on the ticks we skipped & periodic
+//! ticking starts again.
+//!
+//! Idling is also when expired kernel timers' callbacks get run... except after
+//! a panic (see `stop_deferred_work`), when it's back to a plain `hlt`.
+//!
+//! On the PIT, which is shared by all CPUs (& which we can't reprogram without
+//! losing track of time), idling is a plain `hlt`.
+
+use crate::apic::{self, MAX_CPUS};
+use crate::{interrupts, time, timer};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{hlt, interrupts as cpu_interrupts};
+
+/// Longest we stay asleep with nothing scheduled, so tick based things (test
+/// timeouts, the GDB stub's polling) still happen now & then.
+const MAX_IDLE_NANOS: u64 = time::NANOS_PER_SECOND;
+
+/// How much a CPU has idled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdleStats {
+    /// Nanoseconds spent halted.
+    pub idle_nanos: u64,
+    /// Times we've halted.
+    pub halts: u64,
+    /// Of those, how many were tickless.
+    pub tickless_halts: u64,
+}
+
+static STATS: Mutex<[IdleStats; MAX_CPUS]> = Mutex::new(
+    [IdleStats {
+        idle_nanos: 0,
+        halts: 0,
+        tickless_halts: 0,
+    }; MAX_CPUS],
+);
+
+/// Per CPU, the part of a tick that tickless halts have slept through but not
+/// yet added to `interrupts::ticks()`, in nanoseconds times `TIMER_HZ`. Carried
+/// into the next catch up, so `ticks()` doesn't fall further behind each time.
+static TICK_REMAINDERS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// Set by `stop_deferred_work`.
+static DEFERRED_WORK_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// From now on, only ever idle with a plain `hlt`. Panic handlers call this, so
+/// that nothing idling afterwards runs timer callbacks on top of whatever state
+/// the panic left behind.
+pub fn stop_deferred_work() {
+    DEFERRED_WORK_STOPPED.store(true, Ordering::SeqCst);
+}
+
+/// `cpu`'s idle statistics (by local APIC ID).
+pub fn stats(cpu: usize) -> IdleStats {
+    cpu_interrupts::without_interrupts(|| STATS.lock()[cpu.min(MAX_CPUS - 1)])
+}
+
+/// The idle statistics for the CPU we're on.
+pub fn current_stats() -> IdleStats {
+    stats(apic::cpu_index())
+}
+
+/// Halt until the next interrupt, or the next deadline, whichever is first.
+pub fn idle() {
+    // With interrupts off, nothing but an NMI could wake us, so there's
+    // nothing to plan for.
+    if !cpu_interrupts::are_enabled() || DEFERRED_WORK_STOPPED.load(Ordering::SeqCst) {
+        hlt();
+        return;
+    }
+    // Deferred timer work first. If there was any, it may have been what our
+    // caller is waiting for.
+    if timer::run_pending() {
+        return;
+    }
+    cpu_interrupts::disable();
+    if timer::has_pending() {
+        // A timer went off since.
+        cpu_interrupts::enable();
+        return;
+    }
+    let start = time::nanos();
+    let tickless = apic::is_timer_running();
+    if tickless {
+        let wake_at = timer::next_expiry()
+            .unwrap_or(u64::MAX)
+            .min(start + MAX_IDLE_NANOS);
+        if wake_at <= start {
+            // Already due; don't halt at all.
+            cpu_interrupts::enable();
+            return;
+        }
+        let _ = apic::start_timer(time::TimerMode::OneShot {
+            nanos: wake_at - start,
+        });
+    }
+    let start_ticks = interrupts::ticks();
+
+    // Atomically, so an interrupt can't slip in between & leave us halted.
+    cpu_interrupts::enable_and_hlt();
+
+    cpu_interrupts::disable();
+    let idle_nanos = time::nanos() - start;
+    if tickless {
+        let remainder = &TICK_REMAINDERS[apic::cpu_index().min(MAX_CPUS - 1)];
+        let scaled = idle_nanos as u128 * interrupts::TIMER_HZ as u128
+            + remainder.load(Ordering::Relaxed) as u128;
+        let skipped = scaled / time::NANOS_PER_SECOND as u128;
+        remainder.store(
+            (scaled % time::NANOS_PER_SECOND as u128) as u64,
+            Ordering::Relaxed,
+        );
+        interrupts::catch_up_ticks(start_ticks + skipped as u64);
+        timer::advance();
+        let _ = apic::start_timer(time::TimerMode::Periodic {
+            hz: interrupts::TIMER_HZ,
+        });
+    }
+    // Don't wait on the lock if we're idling in a panic that interrupted a reader.
+    if let Some(mut stats) = STATS.try_lock() {
+        let stats = &mut stats[apic::cpu_index()];
+        stats.idle_nanos += idle_nanos;
+        stats.halts += 1;
+        stats.tickless_halts += tickless as u64;
+    }
+    cpu_interrupts::enable();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_tickless_idle() {
+        crate::hpet::init().unwrap();
+        apic::init().unwrap();
+        apic::start_timer(time::TimerMode::Periodic {
+            hz: interrupts::TIMER_HZ,
+        })
+        .unwrap();
+        let (before, start_ticks) = (current_stats(), interrupts::ticks());
+        // ~9 ticks' worth
+        time::sleep(500_000_000);
+        let after = current_stats();
+        apic::stop_timer();
+
+        assert!(after.idle_nanos - before.idle_nanos >= 400_000_000);
+        assert!(after.tickless_halts > before.tickless_halts);
+        // Fewer wake ups than ticks, but the ticks were still counted.
+        assert!(after.halts - before.halts < 9);
+        assert!(interrupts::ticks() - start_ticks >= 8);
+    }
+}
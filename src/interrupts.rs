@@ -2,11 +2,18 @@
 //! Module that handles CPU Exceptions supported by types from
 //! the `x86-64` crate, and interrupts sent to the Intel 8259
 //! chained Programmable Interrupt Controller interface.
+//!
+//! Every PIC line's vector goes to the same dispatch code, which calls
+//! whatever handlers drivers have added with `register_irq` (a line can be
+//! shared, like COM1 & COM3's IRQ 4) & then sends the PIC its EOI.
 
-use crate::{gdt, print, println};
+use crate::{gdt, irq_stats, print, println};
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 /// Intel 8259 has two PIC's; these need to be at a higher interrupt vector
@@ -19,6 +26,23 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 /// another keyboard interrupt.
 pub const PS2_CONTROLLER_IO_PORT: u16 = 0x60;
 
+/// Vector whose handler does nothing, so benchmarks can time the bare cost of
+/// taking an interrupt. Has to match the `int` instruction in `bench_interrupt_round_trip`.
+pub const NOOP_VECTOR: u8 = 0xf0;
+
+/// Vector for the local APIC timer (see `apic.rs`), just past the PICs'.
+pub const APIC_TIMER_VECTOR: u8 = PIC_2_OFFSET + 8;
+/// Vector the local APIC raises when an interrupt goes away before the CPU
+/// takes it. Older APICs ignore the low 4 bits, so it should end in 0xf.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Approximate rate of the timer interrupt. We leave the PIT at its power-on
+/// divisor of 65536, so it ticks at 1193182 / 65536 = ~18.2Hz.
+pub const TIMER_HZ: u64 = 18;
+
+/// Number of timer interrupts since boot; our coarse monotonic clock.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
     // ChainedPics::new is unsafe b/c bad offsets can yield undefined behavior.
     ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
@@ -27,6 +51,7 @@ pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
 /// Define the different hardware interrupt values, starting from the PIC_1_OFFSET
 /// which is high enough to leave room for CPU exceptions and other types of
 /// interrupts.
+/// - Note: only the lines we handle ourselves; drivers just use `register_irq`.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -35,8 +60,68 @@ pub enum InterruptIndex {
 }
 
 impl InterruptIndex {
-    fn as_usize(self) -> usize {
-        usize::from(self as u8)
+    /// The PIC line, i.e. the IRQ number.
+    pub fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
+/// Lines across both PICs.
+pub const IRQ_LINES: usize = 16;
+/// IRQ 2 is where the second PIC chains into the first; nothing else raises it.
+const CASCADE_IRQ: u8 = 2;
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_2_COMMAND_PORT: u16 = 0xa0;
+/// Operation command word 3: have the next read of the command port return
+/// the in-service register.
+const OCW3_READ_ISR: u8 = 0x0b;
+/// Most handlers that can share one line.
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// Called with the line (IRQ number) that was raised.
+pub type IrqHandler = fn(u8, &mut InterruptStackFrame);
+
+static IRQ_HANDLERS: spin::Mutex<[[Option<IrqHandler>; MAX_SHARED_HANDLERS]; IRQ_LINES]> =
+    spin::Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_LINES]);
+
+/// One per line, so they know which one they're for.
+const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [
+    irq_stub::<0>,
+    irq_stub::<1>,
+    irq_stub::<2>,
+    irq_stub::<3>,
+    irq_stub::<4>,
+    irq_stub::<5>,
+    irq_stub::<6>,
+    irq_stub::<7>,
+    irq_stub::<8>,
+    irq_stub::<9>,
+    irq_stub::<10>,
+    irq_stub::<11>,
+    irq_stub::<12>,
+    irq_stub::<13>,
+    irq_stub::<14>,
+    irq_stub::<15>,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// Not a line (0-15) that a device can raise.
+    BadLine(u8),
+    /// `MAX_SHARED_HANDLERS` are already on the line.
+    LineFull(u8),
+}
+
+/// A registered handler; pass it to `unregister_irq` to remove it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrqHandle {
+    line: u8,
+    slot: usize,
+}
+
+impl IrqHandle {
+    pub fn line(&self) -> u8 {
+        self.line
     }
 }
 
@@ -50,15 +135,18 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.debug.set_handler_fn(debug_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); // unsafe
         }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-		idt[InterruptIndex::Keyboard.as_usize()]
-			.set_handler_fn(keyboard_interrupt_handler);
+        for (line, &stub) in IRQ_STUBS.iter().enumerate() {
+            idt[PIC_1_OFFSET as usize + line].set_handler_fn(stub);
+        }
+        idt[APIC_TIMER_VECTOR as usize].set_handler_fn(apic_timer_interrupt_handler);
+        idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        idt[NOOP_VECTOR as usize].set_handler_fn(noop_interrupt_handler);
         idt
     };
 }
@@ -67,35 +155,194 @@ pub fn init_idt() {
     IDT.load()
 }
 
+/// Register the handlers for the lines we look after ourselves: the timer &
+/// the keyboard.
+pub fn init_irqs() {
+    register_irq(InterruptIndex::Timer.irq(), timer_irq).expect("timer IRQ taken");
+    register_irq(InterruptIndex::Keyboard.irq(), keyboard_irq).expect("keyboard IRQ taken");
+}
+
+/// Have `handler` called whenever `line` is raised, alongside any other
+/// handlers already on it, & unmask the line.
+/// - Note: handlers run with interrupts off; the EOI is sent for them.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<IrqHandle, IrqError> {
+    if line as usize >= IRQ_LINES || line == CASCADE_IRQ {
+        return Err(IrqError::BadLine(line));
+    }
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let handlers = &mut handlers[line as usize];
+        let slot = handlers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::LineFull(line))?;
+        handlers[slot] = Some(handler);
+        set_irq_enabled(line, true);
+        Ok(IrqHandle { line, slot })
+    })
+}
+
+/// Remove a handler. The line's masked once it has no handlers left.
+pub fn unregister_irq(handle: IrqHandle) {
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let handlers = &mut handlers[handle.line as usize];
+        handlers[handle.slot] = None;
+        if handlers.iter().all(Option::is_none) {
+            set_irq_enabled(handle.line, false);
+        }
+    });
+}
+
+/// The PICs' in-service registers (the second's in the high byte): the IRQs
+/// they've handed to the CPU & are waiting for an EOI for.
+/// - Note: takes the locked PICs so nobody else is mid-command.
+fn read_in_service(_pics: &mut ChainedPics) -> u16 {
+    let mut command_1 = Port::<u8>::new(PIC_1_COMMAND_PORT);
+    let mut command_2 = Port::<u8>::new(PIC_2_COMMAND_PORT);
+    unsafe {
+        command_1.write(OCW3_READ_ISR);
+        command_2.write(OCW3_READ_ISR);
+        u16::from_le_bytes([command_1.read(), command_2.read()])
+    }
+}
+
+/// Common to every PIC line's vector; see `register_irq`.
+extern "x86-interrupt" fn irq_stub<const LINE: u8>(mut stack_frame: InterruptStackFrame) {
+    // The PICs' lowest priority lines double as their spurious interrupts,
+    // which they don't mark as in service (see `irq_stats.rs`).
+    if (LINE == 7 || LINE == 15) && read_in_service(&mut PICS.lock()) & (1 << LINE) == 0 {
+        irq_stats::count_spurious(LINE);
+        if LINE == 15 {
+            // The first PIC did see IRQ 2 come in from the second, though.
+            unsafe {
+                PICS.lock()
+                    .notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ);
+            }
+        }
+        return;
+    }
+    irq_stats::count(PIC_1_OFFSET + LINE);
+
+    // Copied out of the lock, so handlers can (un)register handlers.
+    let handlers = IRQ_HANDLERS.lock()[LINE as usize];
+    for handler in handlers.into_iter().flatten() {
+        handler(LINE, &mut stack_frame);
+    }
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(PIC_1_OFFSET + LINE);
+    }
+}
+
+/// Timer ticks since interrupts were enabled.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Move `ticks()` up to `ticks`, for the ticks skipped while idling tickless.
+pub fn catch_up_ticks(ticks: u64) {
+    TICKS.fetch_max(ticks, Ordering::Relaxed);
+}
+
+/// Mask or unmask `irq` (0-15) on the PICs. Unmasking one of the second PIC's
+/// lines also unmasks IRQ 2, which it's chained to the first through.
+pub fn set_irq_enabled(irq: u8, enabled: bool) {
+    let update = |mask: u8, bit: u8| if enabled { mask & !(1 << bit) } else { mask | (1 << bit) };
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let [mask1, mask2] = pics.read_masks();
+            let (mask1, mask2) = match irq {
+                0..=7 => (update(mask1, irq), mask2),
+                _ if enabled => (update(mask1, 2), update(mask2, irq - 8)),
+                _ => (mask1, update(mask2, irq - 8)),
+            };
+            pics.write_masks(mask1, mask2);
+        }
+    });
+}
+
+/// Mask or unmask the PIT's IRQ 0, e.g. while another timer ticks instead.
+pub fn set_pit_enabled(enabled: bool) {
+    set_irq_enabled(InterruptIndex::Timer.irq(), enabled);
+}
+
+/// Acknowledge the timer interrupt we're handling, to whichever interrupt
+/// controller it came from.
+pub fn end_of_timer_interrupt() {
+    if crate::apic::is_timer_running() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Timer as u8);
+        }
+    }
+}
+
 /// Breakpoint exceptions are solely used to pause a program when the
-/// breakpoint instruction `int3` is reached.
-extern "x86-interrupt" fn breakpoint_handler(isf: InterruptStackFrame) {
+/// breakpoint instruction `int3` is reached. If a debugger is attached
+/// (see `gdb.rs`) it takes over; otherwise we just dump the stack frame.
+extern "x86-interrupt" fn breakpoint_handler(mut isf: InterruptStackFrame) {
+    if crate::gdb::handle_breakpoint(&mut isf) {
+        return;
+    }
     println!("{:#?}", isf);
 }
 
+/// Raised after each instruction while the trap flag is set, which is how
+/// the GDB stub single-steps.
+extern "x86-interrupt" fn debug_handler(mut isf: InterruptStackFrame) {
+    if crate::gdb::handle_debug(&mut isf) {
+        return;
+    }
+    println!("EXCEPTION: DEBUG\n{:#?}", isf);
+}
+
 /// Called when another exception occurs that is not handled.
 extern "x86-interrupt" fn double_fault_handler(
     isf: InterruptStackFrame,
     _: u64, // Error code is always 0, not needed.
 ) -> ! {
+    crate::backtrace::print();
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", isf);
 }
 
-/// Sent every time the Programmable Interval Timer periodically ticks.
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Everything that happens on a timer tick, whichever timer it came from.
+fn tick(stack_frame: &mut InterruptStackFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
     print!(".");
-    // Need to send an EOI signal to let CPU know the handling of the
-    // last interrupts is complete.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
-    }
+    // Expire kernel timers; their callbacks run later, outside the handler.
+    crate::timer::advance();
+    // Fails the test run if a test hangs (does nothing outside of tests).
+    crate::check_test_timeout();
+    // Lets GDB interrupt us, since the stub's serial port is polled.
+    crate::gdb::poll(stack_frame);
+}
+
+/// Sent every time the Programmable Interval Timer (or the HPET standing in
+/// for it) periodically ticks.
+fn timer_irq(_line: u8, stack_frame: &mut InterruptStackFrame) {
+    tick(stack_frame);
+}
+
+/// The local APIC timer's stand-in for `timer_irq`.
+extern "x86-interrupt" fn apic_timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    irq_stats::count(APIC_TIMER_VECTOR);
+    tick(&mut stack_frame);
+    crate::apic::end_of_interrupt();
+}
+
+/// Nothing to do, & no EOI: the local APIC doesn't expect one for these.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    irq_stats::count(SPURIOUS_VECTOR);
 }
 
 /// Handler for any keyboard interrupt.
 /// We read the scancode, which uses the scancode set 1, 
 /// ["IBM XT"](https://en.wikipedia.org/wiki/IBM_Personal_Computer_XT).
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame){
+fn keyboard_irq(_line: u8, _stack_frame: &mut InterruptStackFrame) {
 	use pc_keyboard::{layouts::Us104Key, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 	use spin::Mutex;
 	use x86_64::instructions::port::Port;
@@ -121,19 +368,75 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
             }
         }
     }
-
-	unsafe {
-		PICS.lock()
-			.notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-	}
 }
 
+/// See `NOOP_VECTOR`.
+extern "x86-interrupt" fn noop_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::bench::Bencher;
+    use crate::kernel_bench;
+    use core::sync::atomic::AtomicU32;
+
+    static FIRST: AtomicU32 = AtomicU32::new(0);
+    static SECOND: AtomicU32 = AtomicU32::new(0);
+
+    /// Nothing's on IRQ 5 on QEMU, so we can raise it ourselves with `int`.
+    fn raise_irq_5() {
+        unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + 5) };
+    }
 
     #[test_case]
     fn test_breakpoint_exception_handler() {
         // Our breakpoint handler should run and then execution should continue
         x86_64::instructions::interrupts::int3();
     }
+
+    #[kernel_bench]
+    fn bench_interrupt_round_trip(b: &mut Bencher) {
+        b.iter(|| unsafe { core::arch::asm!("int {vector}", vector = const NOOP_VECTOR) });
+    }
+
+    #[test_case]
+    fn test_shared_irq_handlers() {
+        let first = register_irq(5, |_, _| {
+            FIRST.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        let second = register_irq(5, |line, _| {
+            assert_eq!(line, 5);
+            SECOND.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        raise_irq_5();
+        assert_eq!((FIRST.load(Ordering::SeqCst), SECOND.load(Ordering::SeqCst)), (1, 1));
+
+        unregister_irq(first);
+        raise_irq_5();
+        assert_eq!((FIRST.load(Ordering::SeqCst), SECOND.load(Ordering::SeqCst)), (1, 2));
+        unregister_irq(second);
+        raise_irq_5();
+        assert_eq!(SECOND.load(Ordering::SeqCst), 2);
+    }
+
+    #[test_case]
+    fn test_spurious_irq_7() {
+        let handle = register_irq(7, |_, _| panic!("handled a spurious IRQ")).unwrap();
+        let before = irq_stats::spurious(7);
+        // Nothing's in service, just like when the PIC raises a spurious IRQ 7.
+        unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + 7) };
+        assert_eq!(irq_stats::spurious(7), before + 1);
+        unregister_irq(handle);
+    }
+
+    #[test_case]
+    fn test_register_irq_errors() {
+        assert_eq!(register_irq(2, |_, _| {}), Err(IrqError::BadLine(2)));
+        assert_eq!(register_irq(16, |_, _| {}), Err(IrqError::BadLine(16)));
+        let handles = [(); MAX_SHARED_HANDLERS].map(|()| register_irq(5, |_, _| {}).unwrap());
+        assert_eq!(register_irq(5, |_, _| {}), Err(IrqError::LineFull(5)));
+        handles.into_iter().for_each(unregister_irq);
+    }
 }
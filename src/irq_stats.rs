@@ -0,0 +1,164 @@
+//! irq_stats.rs
+//! How many interrupts have come in, by vector & by CPU, like Linux's
+//! `/proc/interrupts`. The handlers in `interrupts.rs` count them; `Dump`
+//! formats the table (`dump_to_serial` sends it to COM1) & `Summary` squeezes
+//! it onto one line for the VGA status line.
+//!
+//! Spurious PIC interrupts are counted on their own. If a device drops its
+//! line before the PIC has handed its interrupt to the CPU, the PIC raises its
+//! lowest priority line (IRQ 7, or IRQ 15 on the second PIC) anyway, but
+//! without marking it in service; see `interrupts::irq_stub`.
+
+use crate::apic::{self, MAX_CPUS};
+use crate::interrupts::{APIC_TIMER_VECTOR, PIC_1_OFFSET, SPURIOUS_VECTOR};
+use crate::timer::{Timer, TimerError};
+use crate::{serial_println, time, vga_buffer};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+const VECTORS: usize = 256;
+
+static COUNTS: [[AtomicU64; VECTORS]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; VECTORS] }; MAX_CPUS];
+/// Spurious IRQ 7s & IRQ 15s.
+static SPURIOUS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+/// Keeps the status line up to date, while it's on.
+static STATUS_LINE_TIMER: Mutex<Option<Timer>> = Mutex::new(None);
+
+/// Count an interrupt on `vector`, on this CPU.
+pub fn count(vector: u8) {
+    COUNTS[apic::cpu_index()][vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a spurious IRQ 7 or 15.
+pub fn count_spurious(irq: u8) {
+    SPURIOUS[irq as usize / 8].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Interrupts on `vector` that `cpu` (by local APIC ID) has taken.
+pub fn interrupts(vector: u8, cpu: usize) -> u64 {
+    COUNTS[cpu.min(MAX_CPUS - 1)][vector as usize].load(Ordering::Relaxed)
+}
+
+/// Interrupts on `vector`, across all CPUs.
+pub fn total(vector: u8) -> u64 {
+    (0..MAX_CPUS).map(|cpu| interrupts(vector, cpu)).sum()
+}
+
+/// Spurious interrupts on `irq`; always 0 for anything but 7 & 15.
+pub fn spurious(irq: u8) -> u64 {
+    match irq {
+        7 | 15 => SPURIOUS[irq as usize / 8].load(Ordering::Relaxed),
+        _ => 0,
+    }
+}
+
+/// What raises `vector`, where we know.
+fn describe(vector: u8, f: &mut fmt::Formatter) -> fmt::Result {
+    match vector {
+        APIC_TIMER_VECTOR => write!(f, "APIC timer"),
+        SPURIOUS_VECTOR => write!(f, "APIC spurious"),
+        _ if (PIC_1_OFFSET..PIC_1_OFFSET + 16).contains(&vector) => {
+            write!(f, "PIC IRQ {}", vector - PIC_1_OFFSET)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The whole table: a row for each vector that's had an interrupt & a column
+/// for each CPU that's taken one.
+pub struct Dump;
+
+impl fmt::Display for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut active = [false; MAX_CPUS];
+        for (cpu, active) in active.iter_mut().enumerate() {
+            *active = COUNTS[cpu]
+                .iter()
+                .any(|count| count.load(Ordering::Relaxed) != 0);
+        }
+        let cpus = || (0..MAX_CPUS).filter(|&cpu| active[cpu]);
+
+        write!(f, "    ")?;
+        for cpu in cpus() {
+            // Right aligned over the counts.
+            let width = if cpu < 10 { 7 } else { 6 };
+            write!(f, "{:width$}CPU{}", "", cpu, width = width)?;
+        }
+        writeln!(f)?;
+        for vector in (0..=u8::MAX).filter(|&vector| total(vector) != 0) {
+            write!(f, "{:>3}:", vector)?;
+            for cpu in cpus() {
+                write!(f, " {:>10}", interrupts(vector, cpu))?;
+            }
+            write!(f, "   ")?;
+            describe(vector, f)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "SPU: {:>10}   spurious PIC IRQ 7", spurious(7))?;
+        writeln!(f, "SPU: {:>10}   spurious PIC IRQ 15", spurious(15))
+    }
+}
+
+/// Everything in `Dump` on one line: `vector:count` pairs, then spurious IRQs.
+pub struct Summary;
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IRQs")?;
+        for vector in (0..=u8::MAX).filter(|&vector| total(vector) != 0) {
+            write!(f, " {}:{}", vector, total(vector))?;
+        }
+        write!(f, " spurious:{}", spurious(7) + spurious(15))
+    }
+}
+
+/// Print the table to COM1.
+pub fn dump_to_serial() {
+    serial_println!("{}", Dump);
+}
+
+pub fn update_status_line() {
+    vga_buffer::set_status_line(format_args!("{}", Summary));
+}
+
+/// Keep the VGA status line showing a `Summary`, refreshed every `period`
+/// nanoseconds by a kernel timer.
+pub fn start_status_line(period: u64) -> Result<(), TimerError> {
+    let timer = Timer::periodic(time::nanos(), period, update_status_line)?;
+    *STATUS_LINE_TIMER.lock() = Some(timer);
+    Ok(())
+}
+
+/// Stop refreshing the status line (it keeps showing the last `Summary`).
+pub fn stop_status_line() {
+    STATUS_LINE_TIMER.lock().take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::{self, InterruptIndex};
+    use crate::testing::FmtBuffer;
+
+    #[test_case]
+    fn test_timer_interrupts_counted() {
+        use core::fmt::Write;
+
+        let timer = InterruptIndex::Timer as u8;
+        let (before, ticks) = (total(timer), interrupts::ticks());
+        while interrupts::ticks() < ticks + 2 {
+            x86_64::instructions::hlt();
+        }
+        assert!(total(timer) >= before + 2);
+
+        let mut text = FmtBuffer::new();
+        write!(text, "{}", Dump).unwrap();
+        let text = text.as_str();
+        assert!(text.starts_with("           CPU0"));
+        assert!(text
+            .lines()
+            .any(|line| line.starts_with(" 32:") && line.ends_with("PIC IRQ 0")));
+    }
+}
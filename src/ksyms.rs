@@ -0,0 +1,79 @@
+//! ksyms.rs
+//! Kernel symbol table, used to turn raw addresses (e.g. in backtraces) into
+//! `function+offset`. We can't know our own symbols when compiling, so we
+//! reserve a zeroed `.ksyms` section here, and `tools/ksyms.rs` fills it in
+//! from the linked ELF's `.symtab` before the image gets booted (see
+//! `tools/runner.sh`, our cargo runner). The table format itself lives in
+//! the `parse` crate.
+
+pub use thompson_rust_os_parse::ksyms::{Symbol, SymbolTable};
+
+/// Space reserved for the table. The tool errors out if the symbols don't fit.
+/// - Note: must match `KSYMS_SIZE` in `tools/ksyms.rs`.
+pub const KSYMS_SIZE: usize = 512 * 1024;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+/// The kernel's own symbol table, or `None` if the image wasn't patched by
+/// `tools/ksyms.rs` (e.g. booted without our runner).
+pub fn kernel_symbols() -> Option<SymbolTable<'static>> {
+    // The compiler thinks `KSYMS` is all zeros (it was, before the tool got to
+    // it), so hide where the pointer came from to stop reads being folded away.
+    let base = core::hint::black_box(KSYMS.as_ptr());
+    let bytes = unsafe { core::slice::from_raw_parts(base, KSYMS_SIZE) };
+    SymbolTable::parse(bytes)
+}
+
+/// Look up `addr` in the kernel's symbol table.
+pub fn lookup(addr: u64) -> Option<(Symbol<'static>, u64)> {
+    kernel_symbols()?.lookup(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt;
+    use thompson_rust_os_parse::ksyms::{ENTRY_SIZE, HEADER_SIZE, MAGIC};
+
+    /// Swallows whatever is written to it.
+    struct Sink;
+
+    impl fmt::Write for Sink {
+        fn write_str(&mut self, _: &str) -> fmt::Result {
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn test_fuzz_symbol_table() {
+        use crate::fuzz::{self, MAX_INPUT_LEN};
+        use core::fmt::Write;
+        fuzz::run(|input| {
+            // Random bytes would almost never get past the header checks, so
+            // write a plausible header & let the input be the rest.
+            let (count, slack, rest) = match input {
+                [count, slack, rest @ ..] => (*count as usize % 8, *slack as usize % 16, rest),
+                _ => return,
+            };
+            let mut bytes = [0u8; HEADER_SIZE + MAX_INPUT_LEN];
+            let names_offset = HEADER_SIZE + count * ENTRY_SIZE + slack;
+            bytes[..4].copy_from_slice(MAGIC);
+            bytes[4..8].copy_from_slice(&(count as u32).to_le_bytes());
+            bytes[8..12].copy_from_slice(&(names_offset as u32).to_le_bytes());
+            bytes[HEADER_SIZE..HEADER_SIZE + rest.len()].copy_from_slice(rest);
+            let Some(table) = SymbolTable::parse(&bytes[..HEADER_SIZE + rest.len()]) else {
+                return;
+            };
+            for chunk in rest.chunks_exact(8) {
+                let addr = u64::from_le_bytes(chunk.try_into().unwrap());
+                if let Some((symbol, offset)) = table.lookup(addr) {
+                    assert_eq!(symbol.address + offset, addr);
+                    assert!(symbol.size == 0 || offset < symbol.size as u64);
+                    write!(Sink, "{}", symbol).unwrap();
+                }
+            }
+        });
+    }
+}
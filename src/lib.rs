@@ -12,10 +12,41 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+
+// Lets `#[kernel_test]` name this crate the same way from inside & outside of it.
+extern crate self as thompson_rust_os;
+
+pub use thompson_rust_os_macros::{kernel_bench, kernel_test};
+
+pub mod acpi;
+pub mod apic;
+pub mod backtrace;
+pub mod bench;
+#[cfg(feature = "coverage")]
+pub mod coverage;
+pub mod dmesg;
+pub mod fuzz;
+pub mod fw_cfg;
+pub mod gdb;
 pub mod gdt;
+pub mod hpet;
+pub mod idle;
 pub mod interrupts;
+pub mod irq_stats;
+pub mod ksyms;
+pub mod logger;
+pub mod memory;
+pub mod power;
+pub mod rtc;
 pub mod serial;
+pub mod test_report;
+pub mod testing;
+pub mod time;
+pub mod timer;
 pub mod vga_buffer;
 
 const IOBASE_PORT: u16 = 0xf4;
@@ -28,6 +59,9 @@ pub fn init() {
     interrupts::init_idt();
     // Initialize our interrupt controllers
     unsafe { interrupts::PICS.lock().initialize() };
+    // Then give them something to call.
+    interrupts::init_irqs();
+    serial::init_interrupts().expect("serial IRQs taken");
     // Enable interrupts in the CPU configuration using the `sti` instruction
     x86_64::instructions::interrupts::enable();
 }
@@ -35,12 +69,23 @@ pub fn init() {
 /// Uses `instructions::hlt` -- A thin wrapper over hlt in assembly --
 /// to pause the CPU inbetween interrupts so that we aren't being
 /// enrgy inefficient.
+/// - Note: this is what panics end in, so it's just `hlt`; nothing else gets
+///   run, or reprogrammed, after we've gone wrong. See `idle_loop`.
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+/// What the kernel does once it's booted: `idle::idle` forever, so with the
+/// APIC timer running we only wake up for deadlines, not every tick, & kernel
+/// timers' callbacks get run.
+pub fn idle_loop() -> ! {
+    loop {
+        idle::idle();
+    }
+}
+
 // Wrap codes sent to QEMU's `isa-debug-exit` device for clarity;
 // Using port-mapped I/O to communicate that the kernel should quit when
 // we write one of these codes to the IOBASE_PORT.
@@ -52,6 +97,7 @@ pub enum QemuExitCode {
     Failed = 0b1011,  // 23 after ^
 }
 
+/// - Note: only for tests; `power::shutdown` turns off real machines too.
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
@@ -61,33 +107,204 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+/// How long a test may run before the timer interrupt gives up on it, unless it
+/// asks for longer with `set_test_timeout`. Roughly 10 seconds.
+pub const DEFAULT_TEST_TIMEOUT_TICKS: u64 = 10 * interrupts::TIMER_HZ;
+
+/// Tick count the running test has to finish by; 0 while no test is running.
+static TEST_DEADLINE: AtomicU64 = AtomicU64::new(0);
+/// Name of the running test, for the timeout message.
+static CURRENT_TEST: spin::Mutex<&str> = spin::Mutex::new("");
+/// Set while running a `should_panic` test, so the panic handler counts a panic as a pass.
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Name of the running test.
+fn current_test() -> &'static str {
+    // The test runner only holds this lock with interrupts disabled.
+    CURRENT_TEST.try_lock().map_or("<unknown>", |name| *name)
+}
+
+/// Give the running test `ticks` timer ticks from now to finish, instead of
+/// `DEFAULT_TEST_TIMEOUT_TICKS`. Call at the top of a test that is known to be slow.
+pub fn set_test_timeout(ticks: u64) {
+    TEST_DEADLINE.store(interrupts::ticks() + ticks, Ordering::SeqCst);
+}
+
+/// Called from the timer interrupt: fail the run if the current test has
+/// overrun its deadline.
+/// - Note: tests that hang with interrupts disabled can't be caught this way.
+pub fn check_test_timeout() {
+    let deadline = TEST_DEADLINE.load(Ordering::SeqCst);
+    if deadline == 0 || interrupts::ticks() < deadline {
+        return;
+    }
+    test_report::test_failed(
+        current_test(),
+        test_report::Failure::TimedOut,
+        format_args!("didn't finish by tick {}{}", deadline, fuzz::FailingSeed),
+    );
+    if test_report::SHOW_DIAGNOSTICS {
+        // We were called from the timer interrupt, so this shows where the test was stuck.
+        backtrace::print();
+        dmesg::replay_to_serial();
+    }
+    // We won't be returning from the timer interrupt, so acknowledge it here.
+    interrupts::end_of_timer_interrupt();
+    // Move on to the next test; only returns if no test is running after all.
+    testing::abort_test(false);
+    idle::stop_deferred_work();
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
 // Trait used to make our tests have common behavior (e.g. Test starting and success msging)
 pub trait Testable {
+    fn name(&self) -> &'static str;
     fn run(&self);
+    /// Skip the test, only reporting it as ignored.
+    fn ignored(&self) -> bool {
+        false
+    }
+    /// Benchmarks are the only tests run by `cargo bench` (see `bench.rs`).
+    fn is_bench(&self) -> bool {
+        false
+    }
 }
 
 impl<T: Fn()> Testable for T {
-    fn run(&self) {
+    fn name(&self) -> &'static str {
         // `type_name` is implemented directly by the compiler
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        start_test(self.name(), DEFAULT_TEST_TIMEOUT_TICKS, false);
         self();
-        serial_println!("[ok]");
+        test_report::test_passed(self.name());
+    }
+}
+
+/// A test with options, made by the `#[kernel_test(...)]` attribute rather than
+/// by hand (see `macros/src/lib.rs`).
+pub struct KernelTest {
+    pub name: &'static str,
+    pub test: fn(),
+    /// Passes if the test panics, & fails if it returns.
+    pub should_panic: bool,
+    pub ignore: bool,
+    /// Timer ticks the test may take, if not `DEFAULT_TEST_TIMEOUT_TICKS`.
+    pub timeout_ticks: Option<u64>,
+}
+
+impl Testable for KernelTest {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) {
+        let timeout = self.timeout_ticks.unwrap_or(DEFAULT_TEST_TIMEOUT_TICKS);
+        start_test(self.name, timeout, self.should_panic);
+        (self.test)();
+        if EXPECT_PANIC.swap(false, Ordering::SeqCst) {
+            panic!("test was expected to panic, but returned");
+        }
+        test_report::test_passed(self.name);
+    }
+
+    fn ignored(&self) -> bool {
+        self.ignore
     }
 }
 
+/// Announce a test & arm the watchdog for it.
+fn start_test(name: &'static str, timeout_ticks: u64, should_panic: bool) {
+    test_report::test_started(name);
+    x86_64::instructions::interrupts::without_interrupts(|| *CURRENT_TEST.lock() = name);
+    EXPECT_PANIC.store(should_panic, Ordering::SeqCst);
+    set_test_timeout(timeout_ticks);
+}
+
+/// fw_cfg file (see `fw_cfg.rs`) `tools/runner.sh` passes the filters from
+/// `cargo test -- <filter>...` in, separated by spaces.
+const TEST_FILTER_FILE: &str = "opt/thompson_rust_os/test-filter";
+const MAX_TEST_FILTER_LEN: usize = 256;
+
+/// Like libtest: with no filters everything runs, otherwise a test runs if its
+/// name contains any of the filters.
+fn filter_matches(filters: &str, name: &str) -> bool {
+    let mut filters = filters.split_whitespace().peekable();
+    filters.peek().is_none() || filters.any(|filter| name.contains(filter))
+}
+
 // `tests` - slice of trait object references pointing at the
 // [Fn()](https://doc.rust-lang.org/std/ops/trait.Fn.html) trait.
 // - All functions annotated with `#[test_case]` will have their reference passed here.
+// - A failing test doesn't stop the run; the panic handler (or the timeout
+//   check) jumps back here through `testing::abort_test` & we carry on.
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
+    let mut filter_buf = [0; MAX_TEST_FILTER_LEN];
+    let filters = fw_cfg::read_file(TEST_FILTER_FILE, &mut filter_buf)
+        .and_then(|len| core::str::from_utf8(&filter_buf[..len]).ok())
+        .unwrap_or("");
+    let bench_mode = bench::is_bench_mode();
+    let selected = tests
+        .iter()
+        .filter(|test| filter_matches(filters, test.name()) && (!bench_mode || test.is_bench()));
+
+    let mut summary = test_report::Summary {
+        filtered_out: tests.len() - selected.clone().count(),
+        ..Default::default()
+    };
+    test_report::suite_started(tests.len() - summary.filtered_out);
+    let started_at = interrupts::ticks();
+    for test in selected {
+        if test.ignored() {
+            test_report::test_ignored(test.name());
+            summary.ignored += 1;
+            continue;
+        }
+        // So a failure report only shows what this test logged.
+        dmesg::clear();
+        if !testing::run_guarded(&|| test.run()) {
+            summary.failed += 1;
+        } else if bench_mode {
+            summary.measured += 1;
+        } else {
+            summary.passed += 1;
+        }
+        TEST_DEADLINE.store(0, Ordering::SeqCst);
+        EXPECT_PANIC.store(false, Ordering::SeqCst);
+        fuzz::reset();
     }
-    exit_qemu(QemuExitCode::Success);
+    summary.ticks = interrupts::ticks() - started_at;
+    test_report::suite_finished(&summary);
+    #[cfg(feature = "coverage")]
+    coverage::dump();
+    exit_qemu(if summary.failed == 0 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    });
 }
 
 pub fn test_panic_handler(_info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n\nError: {}\n", _info);
+    if EXPECT_PANIC.swap(false, Ordering::SeqCst) {
+        test_report::test_passed(current_test());
+        testing::abort_test(true);
+    }
+    test_report::test_failed(
+        current_test(),
+        test_report::Failure::Panicked,
+        format_args!("{}{}", _info, fuzz::FailingSeed),
+    );
+    if test_report::SHOW_DIAGNOSTICS {
+        backtrace::print();
+        // Everything the test logged, to see how we got here.
+        dmesg::replay_to_serial();
+    }
+    // Move on to the next test; only returns if the panic wasn't in a test.
+    testing::abort_test(false);
+    idle::stop_deferred_work();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -98,11 +315,54 @@ fn panic(_info: &PanicInfo) -> ! {
     test_panic_handler(_info)
 }
 
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
 /// Entry point for `cargo test`
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    memory::init(x86_64::VirtAddr::new(boot_info.physical_memory_offset));
+    acpi::init().expect("no ACPI tables");
+    // Just the ring buffer; tests only need log lines kept in `dmesg` for failure reports.
+    logger::init(log::LevelFilter::Trace).expect("logger already initialized");
     test_main();
-    hlt_loop();
+    idle_loop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_set_test_timeout_moves_deadline() {
+        set_test_timeout(60 * interrupts::TIMER_HZ);
+        let deadline = TEST_DEADLINE.load(Ordering::SeqCst);
+        assert!(deadline >= interrupts::ticks() + 59 * interrupts::TIMER_HZ);
+        // A few ticks go by without tripping the watchdog.
+        let start = interrupts::ticks();
+        while interrupts::ticks() < start + 3 {
+            x86_64::instructions::hlt();
+        }
+        assert_eq!(TEST_DEADLINE.load(Ordering::SeqCst), deadline);
+    }
+
+    #[test_case]
+    fn test_filter_matches() {
+        assert!(filter_matches("", "a::test_one"));
+        assert!(filter_matches(" \n", "a::test_one"));
+        assert!(filter_matches("two one", "a::test_one"));
+        assert!(filter_matches("a::", "a::test_one"));
+        assert!(!filter_matches("two three", "a::test_one"));
+    }
+
+    #[kernel_test(should_panic)]
+    fn test_should_panic_passes_on_panic() {
+        assert_eq!(0, 1);
+    }
+
+    #[kernel_test(ignore)]
+    fn test_ignored_is_not_run() {
+        panic!("ignored tests shouldn't run");
+    }
 }
@@ -0,0 +1,293 @@
+//! logger.rs
+//! Kernel logging facade. We implement the `log` crate's `Log` trait so anywhere in
+//! the kernel can use `log::info!`, `log::warn!`, etc. instead of picking between
+//! `println!` and `serial_println!`. Every record is stamped with the timer tick it
+//! was logged at, filtered by level (optionally per module path), and then fanned
+//! out to whichever sinks have been registered:
+//! - `VGA_SINK`: the screen, through `println!`
+//! - `SERIAL_SINK`: COM1, through `serial_println!`
+//! - `RING_BUFFER_SINK`: the in-memory `dmesg` ring buffer, which panic handlers
+//!   replay with `dmesg::replay_to_serial`. `init` adds it, so every line that
+//!   passes the filters is kept.
+//!
+//! We don't have a heap, so sinks and module filters live in small fixed-size tables.
+
+use crate::serial::ComPort;
+use core::fmt;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+
+const MAX_SINKS: usize = 4;
+const MAX_MODULE_FILTERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoggerError {
+    /// `log` only allows setting the global logger once.
+    AlreadyInitialized,
+    TooManySinks,
+    TooManyModuleFilters,
+}
+
+/// A single record, ready to be written out by a `Sink`.
+/// Displays as `[   ticks] LEVEL module::path: message` (no trailing newline).
+pub struct LogLine<'a> {
+    pub ticks: u64,
+    pub level: Level,
+    pub module: &'a str,
+    pub args: &'a fmt::Arguments<'a>,
+}
+
+impl fmt::Display for LogLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>8}] {:<5} {}: {}",
+            self.ticks, self.level, self.module, self.args
+        )
+    }
+}
+
+/// Somewhere log lines can be sent. Sinks are called with interrupts disabled.
+pub trait Sink: Sync {
+    fn write_line(&self, line: &LogLine);
+}
+
+/// Writes log lines to the VGA text buffer.
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn write_line(&self, line: &LogLine) {
+        crate::println!("{}", line);
+    }
+}
+
+/// Writes log lines to a serial port.
+pub struct SerialSink(pub ComPort);
+
+impl Sink for SerialSink {
+    fn write_line(&self, line: &LogLine) {
+        crate::serial_println_to!(self.0, "{}", line);
+    }
+}
+
+/// Keeps log lines in memory, in the `dmesg` ring buffer.
+pub struct RingBufferSink;
+
+impl Sink for RingBufferSink {
+    fn write_line(&self, line: &LogLine) {
+        crate::dmesg::record(format_args!("{}", line));
+    }
+}
+
+pub static VGA_SINK: VgaSink = VgaSink;
+pub static SERIAL_SINK: SerialSink = SerialSink(ComPort::Com1);
+pub static RING_BUFFER_SINK: RingBufferSink = RingBufferSink;
+
+/// A default level, plus per-module overrides keyed by module path prefix.
+struct Filters {
+    default: LevelFilter,
+    modules: [Option<(&'static str, LevelFilter)>; MAX_MODULE_FILTERS],
+}
+
+impl Filters {
+    const fn new() -> Filters {
+        Filters {
+            default: LevelFilter::Info,
+            modules: [None; MAX_MODULE_FILTERS],
+        }
+    }
+
+    /// The most specific (longest) matching module filter wins; `prefix` matches
+    /// the module itself and anything nested inside it.
+    fn level_for(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .filter(|(prefix, _)| {
+                module == *prefix
+                    || (module.starts_with(prefix) && module[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn set_module_level(
+        &mut self,
+        prefix: &'static str,
+        level: LevelFilter,
+    ) -> Result<(), LoggerError> {
+        if let Some(entry) = self
+            .modules
+            .iter_mut()
+            .flatten()
+            .find(|(p, _)| *p == prefix)
+        {
+            entry.1 = level;
+            return Ok(());
+        }
+        let slot = self
+            .modules
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(LoggerError::TooManyModuleFilters)?;
+        *slot = Some((prefix, level));
+        Ok(())
+    }
+
+    /// The most verbose level anything could be logged at.
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .map(|&(_, level)| level)
+            .fold(self.default, core::cmp::max)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SinkEntry {
+    sink: &'static dyn Sink,
+    level: LevelFilter,
+}
+
+static FILTERS: Mutex<Filters> = Mutex::new(Filters::new());
+static SINKS: Mutex<[Option<SinkEntry>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            metadata.level() <= FILTERS.lock().level_for(metadata.target())
+        })
+    }
+
+    fn log(&self, record: &Record) {
+        use x86_64::instructions::interrupts;
+
+        // Interrupt handlers log too; don't let one spin on a lock we hold.
+        interrupts::without_interrupts(|| {
+            // Filter on the target, like `enabled` (it's the module path unless
+            // the caller passed `target: ...`).
+            if record.level() > FILTERS.lock().level_for(record.target()) {
+                return;
+            }
+
+            let module = record.module_path().unwrap_or_else(|| record.target());
+
+            let line = LogLine {
+                ticks: crate::interrupts::ticks(),
+                level: record.level(),
+                module,
+                args: record.args(),
+            };
+            // Copy the table out so sinks aren't called with the lock held.
+            let sinks = *SINKS.lock();
+            for entry in sinks.iter().flatten() {
+                if record.level() <= entry.level {
+                    entry.sink.write_line(&line);
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+fn update_max_level() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| log::set_max_level(FILTERS.lock().max()));
+}
+
+/// Install our logger as the `log` crate's global logger, logging at
+/// `default_level` unless overridden with `set_module_level`. Lines are only
+/// kept in the ring buffer until more sinks are added with `add_sink`.
+pub fn init(default_level: LevelFilter) -> Result<(), LoggerError> {
+    log::set_logger(&LOGGER).map_err(|_| LoggerError::AlreadyInitialized)?;
+    set_level(default_level);
+    add_sink(&RING_BUFFER_SINK, LevelFilter::Trace)
+}
+
+/// Change the level used by modules without their own filter.
+pub fn set_level(level: LevelFilter) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        FILTERS.lock().default = level;
+        update_max_level();
+    });
+}
+
+/// Log `module` (a path like `thompson_rust_os::interrupts`), and any modules
+/// nested inside it, at `level` instead of the default.
+pub fn set_module_level(module: &'static str, level: LevelFilter) -> Result<(), LoggerError> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        FILTERS.lock().set_module_level(module, level)?;
+        update_max_level();
+        Ok(())
+    })
+}
+
+/// Send every record at or above `level` (that passed the filters) to `sink`.
+pub fn add_sink(sink: &'static dyn Sink, level: LevelFilter) -> Result<(), LoggerError> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(LoggerError::TooManySinks)?;
+        *slot = Some(SinkEntry { sink, level });
+        Ok(())
+    })
+}
+
+/// Stop sending records to `sink`.
+pub fn remove_sink(sink: &'static dyn Sink) {
+    use x86_64::instructions::interrupts;
+
+    let target = sink as *const dyn Sink as *const ();
+    interrupts::without_interrupts(|| {
+        for slot in SINKS.lock().iter_mut() {
+            if matches!(slot, Some(entry) if entry.sink as *const dyn Sink as *const () == target) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_module_filter_longest_prefix_wins() {
+        let mut filters = Filters::new();
+        filters.default = LevelFilter::Warn;
+        filters
+            .set_module_level("kernel", LevelFilter::Info)
+            .unwrap();
+        filters
+            .set_module_level("kernel::serial", LevelFilter::Trace)
+            .unwrap();
+
+        assert_eq!(filters.level_for("other"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("kernel"), LevelFilter::Info);
+        assert_eq!(filters.level_for("kernel::vga"), LevelFilter::Info);
+        assert_eq!(
+            filters.level_for("kernel::serial::uart"),
+            LevelFilter::Trace
+        );
+        // A prefix only matches whole path segments
+        assert_eq!(filters.level_for("kernelish"), LevelFilter::Warn);
+        assert_eq!(filters.max(), LevelFilter::Trace);
+    }
+}
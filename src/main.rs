@@ -10,13 +10,19 @@
 #![test_runner(thompson_rust_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use thompson_rust_os::println;
+use log::LevelFilter;
+use thompson_rust_os::{logger, println};
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    thompson_rust_os::idle::stop_deferred_work();
     println!("Panic: {}", _info);
+    thompson_rust_os::serial_println!("Panic: {}", _info);
+    thompson_rust_os::backtrace::print();
+    thompson_rust_os::dmesg::replay_to_serial();
     thompson_rust_os::hlt_loop();
 }
 
@@ -26,20 +32,75 @@ fn panic(info: &PanicInfo) -> ! {
     thompson_rust_os::test_panic_handler(info)
 }
 
+// The bootloader calls our entry point with a `BootInfo` argument, so we let its
+// `entry_point!` macro define the real `_start` for us. That also gets us a
+// type-checked signature instead of a bare `extern "C"` fn.
+entry_point!(kernel_main);
+
 /// - `!` specifies this as a diverging fn; entry point should invoke the `exit` syscall.
 /// - Throws linker error by default b/c program depends on C runtime. Build for bare metal to fix.
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello {}!", "World");
 
     // Setup OS lib with Interrupt Descriptor Table registration
     thompson_rust_os::init();
+    thompson_rust_os::memory::init(x86_64::VirtAddr::new(boot_info.physical_memory_offset));
+
+    // Everything goes to serial (and dmesg), only the important stuff to the screen.
+    logger::init(LevelFilter::Debug).expect("logger already initialized");
+    logger::add_sink(&logger::SERIAL_SINK, LevelFilter::Trace).unwrap();
+    logger::add_sink(&logger::VGA_SINK, LevelFilter::Info).unwrap();
+    log::info!("Kernel initialized");
+
+    // Find out what we're running on.
+    match thompson_rust_os::acpi::init() {
+        Ok(acpi) => {
+            if let Some(madt) = acpi.madt {
+                let cpus = madt.processors().filter(|cpu| cpu.enabled).count();
+                log::info!("ACPI: {} CPU(s)", cpus);
+            }
+        }
+        Err(err) => log::warn!("No ACPI tables: {:?}", err),
+    }
+    match thompson_rust_os::hpet::init() {
+        Ok(()) => log::info!("HPET at {}Hz", thompson_rust_os::hpet::frequency()),
+        Err(err) => log::warn!("No HPET: {:?}", err),
+    }
+    // Tick off of this CPU's own timer rather than the shared PIT.
+    let apic_timer = thompson_rust_os::apic::init().and_then(|()| {
+        use thompson_rust_os::{apic, interrupts, time};
+        apic::start_timer(time::TimerMode::Periodic {
+            hz: interrupts::TIMER_HZ,
+        })
+    });
+    match apic_timer {
+        Ok(()) => log::info!("APIC timer at {}Hz", thompson_rust_os::apic::timer_frequency()),
+        Err(err) => log::warn!("Staying on the PIT: {:?}", err),
+    }
+
+    if let Err(err) = thompson_rust_os::time::sync_wall_clock() {
+        log::warn!("No RTC, counting from the epoch: {:?}", err);
+    }
+    log::info!("Time: {}", thompson_rust_os::time::now());
+    // Interrupt counts along the top of the screen.
+    if let Err(err) = thompson_rust_os::irq_stats::start_status_line(
+        thompson_rust_os::time::NANOS_PER_SECOND,
+    ) {
+        log::warn!("No status line: {:?}", err);
+    }
+
+    // Let a host `gdb` attach through COM2 (see README)
+    #[cfg(feature = "gdb-stub")]
+    match thompson_rust_os::gdb::init(thompson_rust_os::serial::ComPort::Com2) {
+        Ok(()) => log::info!("GDB stub listening on COM2"),
+        Err(err) => log::warn!("GDB stub not started: {:?}", err),
+    }
 
     // Call the generated test main in test contexts
     #[cfg(test)]
     test_main();
 
-    thompson_rust_os::hlt_loop();
+    thompson_rust_os::idle_loop();
 }
 
 #[cfg(test)]
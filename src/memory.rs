@@ -0,0 +1,105 @@
+//! memory.rs
+//! Helpers for reaching physical memory from our kernel. The bootloader maps
+//! the complete physical address space into our virtual address space at some
+//! offset (see the `map_physical_memory` bootloader feature in `Cargo.toml`),
+//! and passes that offset to us in the `BootInfo` struct.
+//!
+//! Anything that needs to poke at a physical address that isn't identity
+//! mapped (e.g. VGA plane memory at 0xa0000, ACPI tables, MMIO registers)
+//! should go through `phys_to_virt`; MMIO registers through `map_mmio` first.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, Size1GiB, Size2MiB,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Set once during boot; an offset of 0 means `init` hasn't been called yet.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Record where the bootloader mapped physical memory. Must be called before
+/// any other function in this module is used.
+pub fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
+}
+
+/// The virtual address physical memory starts at.
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst))
+}
+
+/// Translate a physical address into the virtual address that maps to it
+/// through the bootloader's complete physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Returns a mutable reference to the active level 4 page table, found through
+/// the CR3 register.
+/// - Unsafe b/c the caller must not create more than one `&mut` to the table
+///   at a time (which would be aliasing `&mut` references).
+unsafe fn active_level_4_table() -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let virt = phys_to_virt(level_4_table_frame.start_address());
+    &mut *virt.as_mut_ptr()
+}
+
+/// Walk the active page tables to find the physical address `addr` maps to,
+/// or `None` if it isn't mapped. Useful to check an address is safe to access
+/// before touching it.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    // The table is only borrowed for the duration of this call.
+    let table = unsafe { OffsetPageTable::new(active_level_4_table(), physical_memory_offset()) };
+    table.translate_addr(addr)
+}
+
+/// Make `len` bytes of memory mapped I/O registers at physical address `addr`
+/// safe to use through `phys_to_virt`, by turning off caching for the pages
+/// they're on. Returns their virtual address, or `None` if they aren't in the
+/// bootloader's physical memory mapping.
+/// - Note: the bootloader maps with huge pages, so neighbouring memory goes
+///   uncached too. Fine for the MMIO holes below 4GiB, which are all registers.
+/// - Note: we can't map pages that aren't mapped already, b/c we don't have a
+///   frame allocator for the page tables that would need.
+pub fn map_mmio(addr: PhysAddr, len: u64) -> Option<VirtAddr> {
+    let start = phys_to_virt(addr);
+    let end = start + len;
+    // The table is only borrowed for the duration of this call.
+    let mut table =
+        unsafe { OffsetPageTable::new(active_level_4_table(), physical_memory_offset()) };
+    let uncached = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let mut page = start;
+    while page < end {
+        let TranslateResult::Mapped { frame, flags, .. } = table.translate(page) else {
+            return None;
+        };
+        let flags = flags | uncached;
+        // Unsafe b/c changing flags can break memory safety; these pages only
+        // ever get less cacheable.
+        let size = unsafe {
+            match frame {
+                MappedFrame::Size4KiB(_) => {
+                    let page = Page::<Size4KiB>::containing_address(page);
+                    table.update_flags(page, flags).ok()?.flush();
+                    Size4KiB::SIZE
+                }
+                MappedFrame::Size2MiB(_) => {
+                    let page = Page::<Size2MiB>::containing_address(page);
+                    table.update_flags(page, flags).ok()?.flush();
+                    Size2MiB::SIZE
+                }
+                MappedFrame::Size1GiB(_) => {
+                    let page = Page::<Size1GiB>::containing_address(page);
+                    table.update_flags(page, flags).ok()?.flush();
+                    Size1GiB::SIZE
+                }
+            }
+        };
+        page = page.align_down(size) + size;
+    }
+    Some(start)
+}
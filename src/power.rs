@@ -0,0 +1,203 @@
+//! power.rs
+//! Turning the machine off & restarting it, on real hardware as well as QEMU
+//! (`exit_qemu` only works with QEMU's `isa-debug-exit` device).
+//!
+//! - Shutdown enters ACPI sleep state S5 ("soft off"): write the S5 sleep type
+//!   from the DSDT's `\_S5` package into the PM1 control registers, together
+//!   with the sleep enable bit.
+//! - Reboot tries, in order: the FADT's reset register, pulsing the CPU reset
+//!   line through the 8042 keyboard controller, and a triple fault (which
+//!   resets the CPU, & always works).
+//!
+//! - Note: requires `acpi::init` to have been called.
+
+use crate::acpi::{self, AddressSpace, GenericAddress};
+use crate::memory;
+use thompson_rust_os_parse::aml;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::{hlt, interrupts};
+use x86_64::PhysAddr;
+
+/// PM1 control register bits.
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_COMMAND_PORT: u16 = 0x64;
+/// Status bit: the controller hasn't taken the last byte yet.
+const PS2_INPUT_FULL: u8 = 1 << 1;
+/// Command: pulse the CPU's reset line.
+const PS2_PULSE_RESET: u8 = 0xfe;
+
+/// PCI configuration space access mechanism #1.
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+/// An unused port; reading it takes about a microsecond.
+const DELAY_PORT: u16 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// `acpi::init` didn't find the tables (or hasn't been called).
+    NoAcpi,
+    /// No FADT, or one without a PM1a control block.
+    NoPm1Control,
+    /// No DSDT, or we couldn't find `\_S5` in it.
+    NoS5,
+    /// The firmware didn't hand control of power management over to us.
+    AcpiModeNotEnabled,
+    /// We wrote the registers, but we're still here.
+    StillRunning,
+}
+
+/// Busy wait around `micros` microseconds, without needing interrupts.
+fn delay(micros: u32) {
+    let mut port: Port<u8> = Port::new(DELAY_PORT);
+    for _ in 0..micros {
+        unsafe { port.read() };
+    }
+}
+
+/// Switch the chipset from legacy (SMM) to ACPI mode, if it isn't already.
+fn enable_acpi_mode(fadt: &acpi::Fadt, pm1a_control: &mut Port<u16>) -> Result<(), PowerError> {
+    if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+        return Ok(());
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return Err(PowerError::AcpiModeNotEnabled);
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    // The spec gives firmware up to about 3 seconds.
+    for _ in 0..3000 {
+        if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+            return Ok(());
+        }
+        delay(1000);
+    }
+    Err(PowerError::AcpiModeNotEnabled)
+}
+
+/// Ask the chipset to power off. Only returns if that didn't work.
+pub fn acpi_shutdown() -> Result<(), PowerError> {
+    let tables = acpi::tables().ok_or(PowerError::NoAcpi)?;
+    let fadt = tables
+        .fadt
+        .filter(|fadt| fadt.pm1a_control_block != 0)
+        .ok_or(PowerError::NoPm1Control)?;
+    let dsdt = tables.dsdt.ok_or(PowerError::NoS5)?;
+    let (slp_typ_a, slp_typ_b) = aml::s5_sleep_types(dsdt.body()).ok_or(PowerError::NoS5)?;
+
+    let mut pm1a_control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    enable_acpi_mode(&fadt, &mut pm1a_control)?;
+    let sleep = |port: &mut Port<u16>, slp_typ: u8| unsafe {
+        let value = port.read() & !SLP_TYP_MASK;
+        port.write(value | (slp_typ as u16) << SLP_TYP_SHIFT | SLP_EN);
+    };
+    // Both halves of the register block have to be written, b first.
+    if fadt.pm1b_control_block != 0 {
+        sleep(&mut Port::new(fadt.pm1b_control_block as u16), slp_typ_b);
+    }
+    sleep(&mut pm1a_control, slp_typ_a);
+    delay(100_000);
+    Err(PowerError::StillRunning)
+}
+
+/// Turn the machine off. If it can't be, halt with interrupts off instead, so
+/// it's at least safe to pull the plug.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    if let Err(err) = acpi_shutdown() {
+        log::error!("power: ACPI shutdown failed: {:?}; halting", err);
+    }
+    loop {
+        hlt();
+    }
+}
+
+/// Write `value` through the FADT's reset register.
+fn write_reset_register(register: &GenericAddress, value: u8) {
+    let address = register.address;
+    match register.space {
+        AddressSpace::SystemIo => unsafe { Port::<u8>::new(address as u16).write(value) },
+        AddressSpace::SystemMemory => {
+            let Ok(phys) = PhysAddr::try_new(address) else {
+                return;
+            };
+            let virt = memory::phys_to_virt(phys);
+            if memory::translate_addr(virt).is_some() {
+                unsafe { virt.as_mut_ptr::<u8>().write_volatile(value) };
+            }
+        }
+        // Bus 0; device, function & register offset are packed into the address.
+        AddressSpace::PciConfig => {
+            let device = (address >> 32) as u32 & 0x1f;
+            let function = (address >> 16) as u32 & 0x7;
+            let offset = address as u16;
+            let config = 1 << 31 | device << 11 | function << 8 | (offset & 0xfc) as u32;
+            unsafe {
+                Port::<u32>::new(PCI_CONFIG_ADDRESS).write(config);
+                Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3)).write(value);
+            }
+        }
+        AddressSpace::Other(_) => {}
+    }
+}
+
+/// Pulse the CPU's reset line through the 8042 keyboard controller.
+fn pulse_8042_reset() {
+    let mut status: Port<u8> = Port::new(PS2_STATUS_PORT);
+    // Give up waiting for the controller after a while; there may not be one.
+    for _ in 0..10_000 {
+        if unsafe { status.read() } & PS2_INPUT_FULL == 0 {
+            break;
+        }
+        delay(10);
+    }
+    unsafe { Port::<u8>::new(PS2_COMMAND_PORT).write(PS2_PULSE_RESET) };
+}
+
+/// Load an empty IDT & raise an exception: with no handler for it, nor for the
+/// double fault that follows, the CPU resets.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
+
+/// Restart the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(fadt) = acpi::tables().and_then(|tables| tables.fadt) {
+        if let Some(register) = fadt.reset_register {
+            write_reset_register(&register, fadt.reset_value);
+            delay(100_000);
+        }
+    }
+    // Machines without one are old enough to have an 8042 (or emulate one).
+    pulse_8042_reset();
+    delay(100_000);
+    triple_fault();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_qemu_dsdt_declares_s5() {
+        let tables = acpi::tables().unwrap();
+        let dsdt = tables.dsdt.unwrap();
+        assert!(aml::s5_sleep_types(dsdt.body()).is_some());
+        assert_ne!(tables.fadt.unwrap().pm1a_control_block, 0);
+    }
+}
@@ -0,0 +1,271 @@
+//! rtc.rs
+//! Driver for the CMOS [real-time clock](https://wiki.osdev.org/CMOS): the
+//! battery backed clock that keeps the date & time while the machine is off.
+//! `time::now()` reads it once & counts on from there.
+//!
+//! Its registers are in CMOS RAM, which is reached by writing a register's
+//! index to port 0x70, then reading or writing port 0x71:
+//! - 0x00, 0x02, 0x04: seconds, minutes, hours
+//! - 0x07, 0x08, 0x09: day of the month, month, year (of the century)
+//! - 0x0a-0x0c: status registers A (update in progress, periodic rate), B
+//!   (data format, interrupt enables) & C (which interrupt fired)
+//! - the century, at the index in the ACPI FADT, if there is one
+//!
+//! Values are either BCD or binary & hours either 12 or 24 hour, depending on
+//! how the firmware set up status register B.
+
+use crate::interrupts::{self, IrqError, IrqHandle};
+use crate::{acpi, time::DateTime};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+/// Status register A bits.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0f;
+/// Status register B bits.
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+/// Set in the hours register for PM, in 12 hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+/// The RTC's IRQ on the PICs.
+const IRQ: u8 = 8;
+/// The periodic interrupt's base rate; rate setting `n` divides it by 2^(n-1).
+const BASE_HZ: u32 = 32768;
+/// Status register A reads before we give up waiting for an update to finish;
+/// updates take ~2ms, & these are ~1µs each.
+const MAX_UPDATE_POLLS: u32 = 10_000;
+/// Reads of the clock before we give up on getting the same thing twice.
+const MAX_READS: u32 = 5;
+
+/// Periodic interrupts since boot.
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+/// Our IRQ 8 handler, while the periodic interrupt's on.
+static IRQ_HANDLE: Mutex<Option<IrqHandle>> = Mutex::new(None);
+
+lazy_static! {
+    /// The index & data ports, which have to be used as a pair.
+    static ref CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+        index: Port::new(0x70),
+        data: Port::new(0x71),
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The ACPI FADT says there's no CMOS RTC.
+    NotPresent,
+    /// The registers never settled: always mid-update, or changing on every
+    /// read (like with nothing at port 0x71, which reads 0xff).
+    Unstable,
+    /// The periodic interrupt only runs at powers of 2 from 2Hz to 8192Hz.
+    BadRate(u32),
+    /// Couldn't register the IRQ 8 handler.
+    Irq(IrqError),
+}
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    // - Note: bit 7 of the index turns off NMIs; we leave it clear.
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    /// The date & time registers, as-is: seconds, minutes, hours, day, month,
+    /// year & century (0 if `century_register` is 0). `None` if the RTC stays
+    /// mid-update for too long.
+    fn read_clock(&mut self, century_register: u8) -> Option<[u8; 7]> {
+        // The registers are garbage while the RTC updates them (once a second).
+        (0..MAX_UPDATE_POLLS).find(|_| self.read(STATUS_A) & UPDATE_IN_PROGRESS == 0)?;
+        let century = match century_register {
+            0 => 0,
+            register => self.read(register),
+        };
+        Some([
+            self.read(SECONDS),
+            self.read(MINUTES),
+            self.read(HOURS),
+            self.read(DAY),
+            self.read(MONTH),
+            self.read(YEAR),
+            century,
+        ])
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Turn raw registers (see `Cmos::read_clock`) into a date, given the format
+/// status register B says they're in. Without a century, we guess the 2000s.
+fn decode(registers: [u8; 7], status_b: u8) -> DateTime {
+    let [seconds, minutes, hours, day, month, year, century] = registers;
+    let binary = |value: u8| {
+        if status_b & BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let pm = hours & HOUR_PM != 0;
+    let mut hour = binary(hours & !HOUR_PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight & 12 PM is noon.
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    let century = match century {
+        0 => 20,
+        century => binary(century) as u16,
+    };
+    DateTime {
+        year: century * 100 + binary(year) as u16,
+        month: binary(month),
+        day: binary(day),
+        hour,
+        minute: binary(minutes),
+        second: binary(seconds),
+    }
+}
+
+/// The RTC's current date & time.
+pub fn read() -> Result<DateTime, RtcError> {
+    let fadt = acpi::tables().and_then(|tables| tables.fadt);
+    if fadt.is_some_and(|fadt| fadt.boot_arch_flags & acpi::Fadt::BOOT_ARCH_NO_CMOS_RTC != 0) {
+        return Err(RtcError::NotPresent);
+    }
+    let century_register = fadt.map_or(0, |fadt| fadt.century_register);
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        // Read until we get the same thing twice, in case an update started
+        // part way through.
+        let mut last = None;
+        for _ in 0..MAX_READS {
+            match cmos.read_clock(century_register) {
+                Some(registers) if last == Some(registers) => {
+                    return Ok(decode(registers, cmos.read(STATUS_B)));
+                }
+                registers => last = registers,
+            }
+        }
+        Err(RtcError::Unstable)
+    })
+}
+
+/// Have the RTC raise IRQ 8 `hz` times a second; count them with `interrupts()`.
+/// - Note: the HPET steals IRQ 8 while its timer is in legacy replacement mode
+///   (see `hpet.rs`), so this only works while `hpet::start_timer` isn't in use.
+pub fn enable_periodic_interrupt(hz: u32) -> Result<(), RtcError> {
+    if !hz.is_power_of_two() || !(2..=8192).contains(&hz) {
+        return Err(RtcError::BadRate(hz));
+    }
+    let rate = (BASE_HZ / hz).trailing_zeros() as u8 + 1;
+    let mut handle = IRQ_HANDLE.lock();
+    if handle.is_none() {
+        *handle = Some(interrupts::register_irq(IRQ, handle_interrupt).map_err(RtcError::Irq)?);
+    }
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & !RATE_MASK) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | PERIODIC_INTERRUPT);
+        // Nothing more comes until C is read, in case one is already pending.
+        cmos.read(STATUS_C);
+    });
+    Ok(())
+}
+
+pub fn disable_periodic_interrupt() {
+    if let Some(handle) = IRQ_HANDLE.lock().take() {
+        interrupts::unregister_irq(handle);
+    }
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+    });
+}
+
+/// Periodic interrupts since boot.
+pub fn interrupts() -> u64 {
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Our IRQ 8 handler.
+fn handle_interrupt(_irq: u8, _stack_frame: &mut InterruptStackFrame) {
+    // Reading status register C acknowledges the interrupt.
+    CMOS.lock().read(STATUS_C);
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_decode_formats() {
+        let noon = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 34,
+            second: 56,
+        };
+        // BCD, 24 hour
+        let bcd = [0x56, 0x34, 0x12, 0x29, 0x02, 0x24, 0x20];
+        assert_eq!(decode(bcd, HOURS_24), noon);
+        // Binary, 12 hour, no century register
+        assert_eq!(decode([56, 34, 12 | HOUR_PM, 29, 2, 24, 0], BINARY), noon);
+        // 12 AM
+        let midnight = decode([0x56, 0x34, 0x12, 0x29, 0x02, 0x24, 0x20], 0);
+        assert_eq!(midnight.hour, 0);
+        assert_eq!(decode([0, 0, 0x11 | HOUR_PM, 1, 1, 0, 0], 0).hour, 23);
+    }
+
+    #[test_case]
+    fn test_periodic_interrupt() {
+        assert_eq!(
+            enable_periodic_interrupt(1000),
+            Err(RtcError::BadRate(1000))
+        );
+        enable_periodic_interrupt(1024).unwrap();
+        let (start, start_ticks) = (interrupts(), interrupts::ticks());
+        // At least one whole tick (~55ms) passes, so ~56 RTC interrupts.
+        while interrupts::ticks() < start_ticks + 2 {
+            x86_64::instructions::hlt();
+        }
+        disable_periodic_interrupt();
+        assert!(interrupts() - start > 20);
+    }
+}
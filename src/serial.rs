@@ -5,35 +5,490 @@
 //!
 //! This module's print macros should be used in test contexts to report state to
 //! the host machine.
+//!
+//! It also works the other way: bytes sent by the host (e.g. typed into QEMU's
+//! `-serial stdio`) raise an interrupt and get buffered, so a serial port can be
+//! used as an input console with `read_byte` and `read_line`.
+//!
+//! All four legacy COM ports are supported. Each one is probed the first time it
+//! is used, and the ones that exist can be reconfigured and written to through
+//! their own `SerialHandle` / the `serial_print_to!` macros. The plain
+//! `serial_print!` macros & input functions always use COM1.
 
+use crate::interrupts;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
-use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
 
-const SERIAL_PORT: u16 = 0x3F8; // Standard port number for UART's first serial interface
+// Register offsets from a port's base; see https://wiki.osdev.org/Serial_Ports
+const DATA_OFFSET: u16 = 0; // Divisor latch low byte while DLAB is set
+const INTERRUPT_ENABLE_OFFSET: u16 = 1; // Divisor latch high byte while DLAB is set
+const FIFO_CONTROL_OFFSET: u16 = 2;
+const LINE_CONTROL_OFFSET: u16 = 3;
+const MODEM_CONTROL_OFFSET: u16 = 4;
+const LINE_STATUS_OFFSET: u16 = 5;
+const SCRATCH_OFFSET: u16 = 7;
+// Interrupt enable, line control & line status bits
+const RECEIVED_DATA_AVAILABLE: u8 = 1;
+const DIVISOR_LATCH_ACCESS: u8 = 1 << 7;
+const DATA_READY: u8 = 1;
+const TRANSMIT_HOLDING_EMPTY: u8 = 1 << 5;
+// Modem control bits
+const MODEM_CONTROL_DEFAULT: u8 = 0x0b; // DTR, RTS & OUT2
+/// The UART's clock; the baud rate is this divided by a 16 bit divisor.
+const MAX_BAUD: u32 = 115_200;
+/// Bytes received, but not yet read, past this many are dropped.
+const INPUT_BUFFER_SIZE: usize = 256;
 
-lazy_static! {
-    pub static ref SERIAL: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(SERIAL_PORT) };
-        serial_port.init();
-        Mutex::new(serial_port)
+/// The four legacy PC serial ports. Their port numbers and IRQs are a
+/// convention rather than something we can discover, so we probe for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// PIC line the port raises interrupts on; COM3 & COM4 share with COM1 & COM2.
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five = 0,
+    Six = 1,
+    Seven = 2,
+    Eight = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None = 0b000,
+    Odd = 0b001,
+    Even = 0b011,
+    Mark = 0b101,
+    Space = 0b111,
+}
+
+/// - Note: `Two` really means 1.5 stop bits when using 5 data bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One = 0,
+    Two = 1,
+}
+
+/// How the line is driven; both ends need to agree on all of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineConfig {
+    /// 38400 baud, 8-N-1; what we've always used, and what QEMU doesn't care about.
+    pub const DEFAULT: LineConfig = LineConfig {
+        baud: 38_400,
+        data_bits: DataBits::Eight,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
     };
+
+    /// Layout of the line control register, sans the DLAB bit.
+    fn line_control(&self) -> u8 {
+        (self.data_bits as u8) | (self.stop_bits as u8) << 2 | (self.parity as u8) << 3
+    }
+
+    /// Baud rate is set by dividing `MAX_BAUD`, so only exact divisors work.
+    fn divisor(&self) -> Result<u16, SerialError> {
+        if self.baud == 0 || self.baud > MAX_BAUD || !MAX_BAUD.is_multiple_of(self.baud) {
+            return Err(SerialError::UnsupportedBaud(self.baud));
+        }
+        Ok((MAX_BAUD / self.baud) as u16)
+    }
+}
+
+impl Default for LineConfig {
+    fn default() -> Self {
+        LineConfig::DEFAULT
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// Probing didn't find a UART at this port.
+    NotPresent(ComPort),
+    UnsupportedBaud(u32),
+}
+
+/// Minimal driver for a port-mapped 16550 UART.
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    /// # Safety
+    /// The caller must make sure `base` really is a UART's base port.
+    pub const unsafe fn new(base: u16) -> Uart {
+        Uart { base }
+    }
+
+    /// Detect a UART by writing to its scratch register and reading it back;
+    /// with nothing at `base` the bus just floats (usually reads 0xff).
+    pub fn probe(base: u16) -> bool {
+        let mut scratch = Port::<u8>::new(base + SCRATCH_OFFSET);
+        [0x5a, 0xa5].iter().all(|&value| unsafe {
+            scratch.write(value);
+            scratch.read() == value
+        })
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// (Re)program the line settings, enable the FIFOs, and enable the
+    /// received-data-available interrupt so we never need to poll for input.
+    pub fn init(&mut self, config: &LineConfig) -> Result<(), SerialError> {
+        let divisor = config.divisor()?;
+        unsafe {
+            self.port(INTERRUPT_ENABLE_OFFSET).write(0);
+            // The divisor lives where the data & interrupt enable registers are
+            // while the divisor latch access bit is set.
+            self.port(LINE_CONTROL_OFFSET).write(DIVISOR_LATCH_ACCESS);
+            self.port(DATA_OFFSET).write(divisor as u8);
+            self.port(INTERRUPT_ENABLE_OFFSET).write((divisor >> 8) as u8);
+            self.port(LINE_CONTROL_OFFSET).write(config.line_control());
+            // Enable & clear the FIFOs, interrupt once 14 bytes are waiting
+            // (or a few character times after the last byte arrived).
+            self.port(FIFO_CONTROL_OFFSET).write(0xc7);
+            // Data terminal ready, request to send & OUT2, which gates the
+            // UART's interrupt line to the PIC.
+            self.port(MODEM_CONTROL_OFFSET).write(MODEM_CONTROL_DEFAULT);
+            self.port(INTERRUPT_ENABLE_OFFSET).write(RECEIVED_DATA_AVAILABLE);
+        }
+        Ok(())
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.port(LINE_STATUS_OFFSET).read() }
+    }
+
+    /// Wait for room in the transmitter, then send `byte`.
+    pub fn send(&mut self, byte: u8) {
+        while self.line_status() & TRANSMIT_HOLDING_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.port(DATA_OFFSET).write(byte) };
+    }
+
+    /// Turn the received-data-available interrupt on or off. With it off, received
+    /// bytes stay in the UART until read with `try_receive`.
+    pub fn set_receive_interrupt(&mut self, enabled: bool) {
+        let value = if enabled { RECEIVED_DATA_AVAILABLE } else { 0 };
+        unsafe { self.port(INTERRUPT_ENABLE_OFFSET).write(value) };
+    }
+
+    /// Read a byte straight from the UART if one has arrived. Normally the
+    /// interrupt handler gets to it first; this is for interrupts-off code.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.line_status() & DATA_READY == 0 {
+            return None;
+        }
+        Some(unsafe { self.port(DATA_OFFSET).read() })
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Whether each port was found when probing; the interrupt handler checks this
+/// rather than `PORTS` so it can never block on the lazy initialization.
+static PRESENT: [AtomicBool; 4] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+lazy_static! {
+    static ref PORTS: [Option<Mutex<Uart>>; 4] = ComPort::ALL.map(|com| {
+        if !Uart::probe(com.base()) {
+            return None;
+        }
+        let mut uart = unsafe { Uart::new(com.base()) };
+        uart.init(&LineConfig::DEFAULT).expect("default line config is valid");
+        PRESENT[com.index()].store(true, Ordering::SeqCst);
+        Some(Mutex::new(uart))
+    });
+}
+
+/// Fixed-size FIFO ring of received bytes, filled from the serial IRQ handlers.
+/// When full, new bytes are dropped rather than overwriting unread input.
+struct InputBuffer {
+    bytes: [u8; INPUT_BUFFER_SIZE],
+    head: usize, // index of the oldest unread byte
+    len: usize,
+}
+
+impl InputBuffer {
+    const fn new() -> InputBuffer {
+        InputBuffer {
+            bytes: [0; INPUT_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == INPUT_BUFFER_SIZE {
+            return false;
+        }
+        self.bytes[(self.head + self.len) % INPUT_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static INPUTS: [Mutex<InputBuffer>; 4] = [
+    Mutex::new(InputBuffer::new()),
+    Mutex::new(InputBuffer::new()),
+    Mutex::new(InputBuffer::new()),
+    Mutex::new(InputBuffer::new()),
+];
+
+/// Take the serial IRQs (3 & 4), so received bytes get buffered for `read_byte`.
+pub fn init_interrupts() -> Result<(), interrupts::IrqError> {
+    // Probe (& so program) the ports now, rather than on first print, so COM1's
+    // receive interrupt is on even if nothing's been printed yet.
+    lazy_static::initialize(&PORTS);
+    for irq in [ComPort::Com2.irq(), ComPort::Com1.irq()] {
+        interrupts::register_irq(irq, handle_receive_interrupt)?;
+    }
+    Ok(())
+}
+
+/// The serial IRQ handler. Two ports share each IRQ line, so drain every byte
+/// from every present port wired to `irq`.
+fn handle_receive_interrupt(irq: u8, _stack_frame: &mut InterruptStackFrame) {
+    for com in ComPort::ALL {
+        if com.irq() != irq || !PRESENT[com.index()].load(Ordering::SeqCst) {
+            continue;
+        }
+        let mut line_status = Port::<u8>::new(com.base() + LINE_STATUS_OFFSET);
+        let mut data = Port::<u8>::new(com.base() + DATA_OFFSET);
+        let mut input = INPUTS[com.index()].lock();
+
+        while unsafe { line_status.read() } & DATA_READY != 0 {
+            input.push(unsafe { data.read() });
+        }
+    }
+}
+
+/// A detected COM port; get one from `handle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialHandle {
+    com: ComPort,
+}
+
+/// Get a handle to `com`, or `None` if there's no UART there.
+pub fn handle(com: ComPort) -> Option<SerialHandle> {
+    PORTS[com.index()].as_ref().map(|_| SerialHandle { com })
+}
+
+impl SerialHandle {
+    pub fn com_port(&self) -> ComPort {
+        self.com
+    }
+
+    fn uart(&self) -> &'static Mutex<Uart> {
+        PORTS[self.com.index()]
+            .as_ref()
+            .expect("handles are only created for present ports")
+    }
+
+    /// Change the baud rate, parity, data & stop bits used by this port.
+    /// - Note: this turns the receive interrupt back on, if it was `set_polled`.
+    pub fn configure(&self, config: &LineConfig) -> Result<(), SerialError> {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| self.uart().lock().init(config))?;
+        self.unmask_irq();
+        Ok(())
+    }
+
+    /// Make sure the PIC passes on our receive interrupts; the BIOS may have
+    /// left the line masked.
+    fn unmask_irq(&self) {
+        interrupts::set_irq_enabled(self.com.irq(), true);
+    }
+
+    pub fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        use core::fmt::Write;
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| self.uart().lock().write_fmt(args))
+    }
+
+    /// Send raw bytes as-is, without requiring them to be valid UTF-8.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            let mut uart = self.uart().lock();
+            for &byte in bytes {
+                uart.send(byte);
+            }
+        });
+    }
+
+    /// Take over reading from this port: stop buffering received bytes from the
+    /// IRQ handler, leaving them in the UART to be read with `try_receive`.
+    /// Used when something needs to poll the port, e.g. the GDB stub.
+    pub fn set_polled(&self, polled: bool) {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| self.uart().lock().set_receive_interrupt(!polled));
+        // Left unmasked when polled, b/c another port may share the line.
+        if !polled {
+            self.unmask_irq();
+        }
+    }
+
+    /// Read a byte straight from the UART, bypassing the input buffer; see `set_polled`.
+    pub fn try_receive(&self) -> Option<u8> {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| self.uart().lock().try_receive())
+    }
+
+    /// Take the oldest received byte, if there is one. Never blocks.
+    pub fn read_byte(&self) -> Option<u8> {
+        use x86_64::instructions::interrupts;
+
+        // The IRQ handler takes this lock too, so hold it with interrupts off.
+        interrupts::without_interrupts(|| INPUTS[self.com.index()].lock().pop())
+    }
+
+    /// Wait for the next received byte, halting the CPU in between interrupts.
+    pub fn read_byte_blocking(&self) -> u8 {
+        use x86_64::instructions::interrupts;
+
+        loop {
+            // Check for input & halt with interrupts disabled so a byte arriving
+            // in between can't be missed; `enable_and_hlt` re-enables atomically.
+            interrupts::disable();
+            if let Some(byte) = INPUTS[self.com.index()].lock().pop() {
+                interrupts::enable();
+                return byte;
+            }
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    /// Read a line of input into `buf`, echoing it back as it is typed, and
+    /// return the number of bytes read (not counting the line terminator).
+    /// Handles backspace; input past the end of `buf` is discarded.
+    pub fn read_line(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        loop {
+            match self.read_byte_blocking() {
+                b'\r' | b'\n' => {
+                    let _ = self.write_fmt(format_args!("\n"));
+                    return len;
+                }
+                // Backspace or DEL, depending on the terminal
+                0x08 | 0x7f => {
+                    if len > 0 {
+                        len -= 1;
+                        let _ = self.write_fmt(format_args!("\x08 \x08"));
+                    }
+                }
+                byte if len < buf.len() => {
+                    buf[len] = byte;
+                    len += 1;
+                    // As-is; `byte as char` would send non-ASCII as 2 bytes of UTF-8.
+                    self.write_bytes(&[byte]);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Take the oldest byte received on COM1, if there is one. Never blocks.
+pub fn read_byte() -> Option<u8> {
+    handle(ComPort::Com1).and_then(|com1| com1.read_byte())
+}
+
+/// Wait for the next byte received on COM1.
+pub fn read_byte_blocking() -> u8 {
+    handle(ComPort::Com1)
+        .expect("COM1 not present")
+        .read_byte_blocking()
+}
+
+/// Read a line of input from COM1; see `SerialHandle::read_line`.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    handle(ComPort::Com1).expect("COM1 not present").read_line(buf)
 }
 
 /* Similar to the VGA Buffer, add macros to make interacting w/ the SerialPort simpler */
 
 // I don't hide this from docs like the tutorial does b/c this is all just for learning/reference
 pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    _print_to(ComPort::Com1, args);
+}
 
-    interrupts::without_interrupts(|| {
-        // `SerialPort` implements the `fmt::Write` trait
-        SERIAL
-            .lock()
+/// Output to a port that wasn't detected is silently dropped.
+pub fn _print_to(com: ComPort, args: ::core::fmt::Arguments) {
+    if let Some(serial) = handle(com) {
+        serial
             .write_fmt(args)
             .expect("Printing to serial failed");
-    });
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -52,3 +507,113 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Prints to the host through a specific `ComPort`.
+#[macro_export]
+macro_rules! serial_print_to {
+    ($port:expr, $($arg:tt)*) => {
+        $crate::serial::_print_to($port, format_args!($($arg)*));
+    };
+}
+
+/// Prints to the host through a specific `ComPort`, appending a newline.
+#[macro_export]
+macro_rules! serial_println_to {
+    ($port:expr) => ($crate::serial_print_to!($port, "\n"));
+    ($port:expr, $fmt:expr) => ($crate::serial_print_to!($port, concat!($fmt, "\n")));
+    ($port:expr, $fmt:expr, $($arg:tt)*) => ($crate::serial_print_to!($port,
+        concat!($fmt, "\n"), $($arg)*));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_input_buffer_is_fifo() {
+        let mut input = InputBuffer::new();
+        assert!(input.push(b'a'));
+        assert!(input.push(b'b'));
+        assert_eq!(input.pop(), Some(b'a'));
+        assert_eq!(input.pop(), Some(b'b'));
+        assert_eq!(input.pop(), None);
+    }
+
+    #[test_case]
+    fn test_input_buffer_drops_bytes_when_full() {
+        let mut input = InputBuffer::new();
+        // Start part way through so we also exercise wrapping around the end.
+        input.push(0);
+        input.pop();
+        for i in 0..INPUT_BUFFER_SIZE {
+            assert!(input.push(i as u8));
+        }
+        assert!(!input.push(0xff));
+        for i in 0..INPUT_BUFFER_SIZE {
+            assert_eq!(input.pop(), Some(i as u8));
+        }
+        assert_eq!(input.pop(), None);
+    }
+
+    #[test_case]
+    fn test_line_config_divisor() {
+        let mut config = LineConfig::DEFAULT;
+        assert_eq!(config.divisor(), Ok(3));
+        config.baud = 115_200;
+        assert_eq!(config.divisor(), Ok(1));
+        config.baud = 50_000;
+        assert_eq!(config.divisor(), Err(SerialError::UnsupportedBaud(50_000)));
+        config.baud = 0;
+        assert_eq!(config.divisor(), Err(SerialError::UnsupportedBaud(0)));
+    }
+
+    #[test_case]
+    fn test_line_control_layout() {
+        assert_eq!(LineConfig::DEFAULT.line_control(), 0b0000_0011);
+        let config = LineConfig {
+            baud: 9600,
+            data_bits: DataBits::Seven,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+        };
+        assert_eq!(config.line_control(), 0b0001_1110);
+    }
+
+    #[test_case]
+    fn test_com1_is_detected() {
+        assert!(handle(ComPort::Com1).is_some());
+    }
+
+    /// Modem control bit that wires the UART's output straight to its input.
+    const LOOPBACK: u8 = 1 << 4;
+
+    /// Have COM1 receive `byte` from itself, in loopback mode.
+    fn loop_back(byte: u8) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut uart = PORTS[ComPort::Com1.index()].as_ref().unwrap().lock();
+            let mut modem_control = uart.port(MODEM_CONTROL_OFFSET);
+            unsafe { modem_control.write(MODEM_CONTROL_DEFAULT | LOOPBACK) };
+            uart.send(byte);
+            while uart.line_status() & DATA_READY == 0 {
+                core::hint::spin_loop();
+            }
+            unsafe { modem_control.write(MODEM_CONTROL_DEFAULT) };
+        });
+    }
+
+    #[test_case]
+    fn test_receive_interrupt() {
+        while read_byte().is_some() {}
+        loop_back(b'x');
+        // The byte only gets to the input buffer through IRQ 4.
+        let start = interrupts::ticks();
+        while interrupts::ticks() < start + 5 {
+            if let Some(byte) = read_byte() {
+                assert_eq!(byte, b'x');
+                return;
+            }
+            x86_64::instructions::hlt();
+        }
+        panic!("nothing received");
+    }
+}
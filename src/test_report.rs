@@ -0,0 +1,278 @@
+//! test_report.rs
+//! Formats the test runner's results on serial. By default we print the
+//! familiar `name...\t[ok]` lines for people; for CI, one of these cargo
+//! features switches to something a machine can parse:
+//! - `test-output-tap`: [TAP version 13](https://testanything.org/tap-version-13-specification.html)
+//! - `test-output-json`: the JSON lines libtest prints with `--format json`
+//!
+//! Durations are in timer ticks (see `interrupts::TIMER_HZ`).
+//! - Note: the machine readable formats leave out backtraces & dmesg replays
+//!   (see `SHOW_DIAGNOSTICS`), which would break up their output.
+
+use crate::bench::Stats;
+use crate::{interrupts, serial_print, serial_println};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(all(feature = "test-output-tap", feature = "test-output-json"))]
+compile_error!("pick one of the `test-output-tap` & `test-output-json` features");
+
+/// Whether free-form output (backtraces, dmesg) can go between test results.
+pub const SHOW_DIAGNOSTICS: bool = !cfg!(any(
+    feature = "test-output-tap",
+    feature = "test-output-json"
+));
+
+/// Tick the running test started at.
+static STARTED_AT: AtomicU64 = AtomicU64::new(0);
+/// Number of the running test; TAP counts from 1.
+static TEST_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+/// Why a test failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Panicked,
+    TimedOut,
+}
+
+/// Final counts for a test run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    /// Benchmarks measured, under `cargo bench`.
+    pub measured: usize,
+    /// Tests skipped b/c they didn't match the name filter.
+    pub filtered_out: usize,
+    /// Ticks the whole run took.
+    pub ticks: u64,
+}
+
+fn elapsed() -> u64 {
+    interrupts::ticks() - STARTED_AT.load(Ordering::SeqCst)
+}
+
+/// Ticks to (approximate) seconds, which is what libtest reports.
+fn seconds(ticks: u64) -> f64 {
+    ticks as f64 / interrupts::TIMER_HZ as f64
+}
+
+pub fn suite_started(test_count: usize) {
+    TEST_NUMBER.store(0, Ordering::SeqCst);
+    if cfg!(feature = "test-output-tap") {
+        serial_println!("TAP version 13\n1..{}", test_count);
+    } else if cfg!(feature = "test-output-json") {
+        serial_println!(
+            r#"{{ "type": "suite", "event": "started", "test_count": {} }}"#,
+            test_count
+        );
+    } else {
+        serial_println!("Running {} tests", test_count);
+    }
+}
+
+pub fn test_started(name: &str) {
+    TEST_NUMBER.fetch_add(1, Ordering::SeqCst);
+    STARTED_AT.store(interrupts::ticks(), Ordering::SeqCst);
+    if cfg!(feature = "test-output-tap") {
+        // Nothing until we know how it went.
+    } else if cfg!(feature = "test-output-json") {
+        serial_println!(
+            r#"{{ "type": "test", "event": "started", "name": "{}" }}"#,
+            Escaped(format_args!("{}", name))
+        );
+    } else {
+        serial_print!("{}...\t", name);
+    }
+}
+
+pub fn test_ignored(name: &str) {
+    let number = TEST_NUMBER.fetch_add(1, Ordering::SeqCst) + 1;
+    if cfg!(feature = "test-output-tap") {
+        serial_println!("ok {} - {} # SKIP ignored", number, name);
+    } else if cfg!(feature = "test-output-json") {
+        serial_println!(
+            r#"{{ "type": "test", "event": "ignored", "name": "{}" }}"#,
+            Escaped(format_args!("{}", name))
+        );
+    } else {
+        serial_println!("{}...\t[ignored]", name);
+    }
+}
+
+pub fn test_passed(name: &str) {
+    let ticks = elapsed();
+    if cfg!(feature = "test-output-tap") {
+        let number = TEST_NUMBER.load(Ordering::SeqCst);
+        serial_println!("ok {} - {} # ticks={}", number, name, ticks);
+    } else if cfg!(feature = "test-output-json") {
+        serial_println!(
+            r#"{{ "type": "test", "event": "ok", "name": "{}", "exec_time": {:.3}, "exec_ticks": {} }}"#,
+            Escaped(format_args!("{}", name)),
+            seconds(ticks),
+            ticks
+        );
+    } else {
+        serial_println!("[ok]");
+    }
+}
+
+/// Cycles per iteration, from a benchmark run under `cargo bench`.
+pub fn bench_measured(name: &str, stats: &Stats) {
+    if cfg!(feature = "test-output-tap") {
+        let number = TEST_NUMBER.load(Ordering::SeqCst);
+        serial_println!(
+            "ok {} - {} # cycles: min={} median={} mean={} max={} samples={}",
+            number,
+            name,
+            stats.min,
+            stats.median,
+            stats.mean,
+            stats.max,
+            stats.samples
+        );
+    } else if cfg!(feature = "test-output-json") {
+        // libtest's "deviation" is the spread of its samples.
+        serial_println!(
+            r#"{{ "type": "bench", "name": "{}", "median": {}, "deviation": {}, "min": {}, "mean": {}, "max": {}, "samples": {}, "unit": "cycles" }}"#,
+            Escaped(format_args!("{}", name)),
+            stats.median,
+            stats.max - stats.min,
+            stats.min,
+            stats.mean,
+            stats.max,
+            stats.samples
+        );
+    } else {
+        serial_println!(
+            "[bench] cycles: min={} median={} mean={} max={} samples={}",
+            stats.min,
+            stats.median,
+            stats.mean,
+            stats.max,
+            stats.samples
+        );
+    }
+}
+
+/// `message` is e.g. the panic message.
+pub fn test_failed(name: &str, failure: Failure, message: fmt::Arguments) {
+    let ticks = elapsed();
+    if cfg!(feature = "test-output-tap") {
+        let number = TEST_NUMBER.load(Ordering::SeqCst);
+        // A YAML block; double quoted YAML strings escape like JSON ones.
+        serial_println!(
+            "not ok {} - {}\n  ---\n  failure: {:?}\n  message: \"{}\"\n  ticks: {}\n  ...",
+            number,
+            name,
+            failure,
+            Escaped(message),
+            ticks
+        );
+    } else if cfg!(feature = "test-output-json") {
+        let prefix = match failure {
+            Failure::Panicked => "panicked: ",
+            Failure::TimedOut => "timed out: ",
+        };
+        serial_println!(
+            r#"{{ "type": "test", "event": "failed", "name": "{}", "exec_time": {:.3}, "exec_ticks": {}, "stdout": "{}{}" }}"#,
+            Escaped(format_args!("{}", name)),
+            seconds(ticks),
+            ticks,
+            prefix,
+            Escaped(message)
+        );
+    } else {
+        let status = match failure {
+            Failure::Panicked => "[failed]",
+            Failure::TimedOut => "[timeout]",
+        };
+        serial_println!("{}\n\nError: {}\n", status, message);
+    }
+}
+
+pub fn suite_finished(summary: &Summary) {
+    let ok = summary.failed == 0;
+    if cfg!(feature = "test-output-tap") {
+        serial_println!(
+            "# passed {}, failed {}, ignored {}, measured {}, filtered out {}, ticks {}",
+            summary.passed,
+            summary.failed,
+            summary.ignored,
+            summary.measured,
+            summary.filtered_out,
+            summary.ticks
+        );
+    } else if cfg!(feature = "test-output-json") {
+        serial_println!(
+            r#"{{ "type": "suite", "event": "{}", "passed": {}, "failed": {}, "ignored": {}, "measured": {}, "filtered_out": {}, "exec_time": {:.3}, "exec_ticks": {} }}"#,
+            if ok { "ok" } else { "failed" },
+            summary.passed,
+            summary.failed,
+            summary.ignored,
+            summary.measured,
+            summary.filtered_out,
+            seconds(summary.ticks),
+            summary.ticks
+        );
+    } else {
+        serial_println!(
+            "\ntest result: {}. {} passed; {} failed; {} ignored; {} measured; {} filtered out",
+            if ok { "ok" } else { "FAILED" },
+            summary.passed,
+            summary.failed,
+            summary.ignored,
+            summary.measured,
+            summary.filtered_out
+        );
+    }
+}
+
+/// Formats its arguments as the inside of a JSON string. Streams straight
+/// through, b/c we have no heap to build the string in first.
+struct Escaped<'a>(fmt::Arguments<'a>);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct Escaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+        impl fmt::Write for Escaper<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    match c {
+                        '"' => self.0.write_str("\\\"")?,
+                        '\\' => self.0.write_str("\\\\")?,
+                        '\n' => self.0.write_str("\\n")?,
+                        '\r' => self.0.write_str("\\r")?,
+                        '\t' => self.0.write_str("\\t")?,
+                        c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+                        c => fmt::Write::write_char(self.0, c)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        fmt::write(&mut Escaper(f), self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FmtBuffer;
+
+    #[test_case]
+    fn test_escaped_json_string() {
+        use core::fmt::Write;
+        let mut text = FmtBuffer::new();
+        write!(
+            text,
+            "{}",
+            Escaped(format_args!("say \"{}\"\n\tC:\\ {}", "hi", '\x07'))
+        )
+        .unwrap();
+        assert_eq!(text.as_str(), r#"say \"hi\"\n\tC:\\ \u0007"#);
+    }
+}
@@ -0,0 +1,159 @@
+//! testing.rs
+//! Lets `test_runner` carry on after a test fails. There's no unwinding in our
+//! kernel (panic = "abort"), so instead we do a poor man's setjmp/longjmp:
+//! - `run_guarded` saves the callee-saved registers & stack pointer before
+//!   calling the test.
+//! - When the test panics (or times out), `abort_test` throws away the test's
+//!   stack frames by restoring that stack pointer, and `run_guarded` returns
+//!   to the runner as if the test had returned, with whether it passed (e.g.
+//!   for tests that are supposed to panic).
+//!
+//! Nothing the failed test was doing gets cleaned up: destructors don't run and
+//! any spin locks it held stay locked. Good enough to report the other tests.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Stack pointer to resume from in `abort_test`; 0 when no test is running.
+/// Nested `run_guarded`s save the outer one's on their stack & put it back.
+static RESUME_RSP: AtomicU64 = AtomicU64::new(0);
+
+global_asm!(
+    ".global __testing_run_guarded",
+    "__testing_run_guarded:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // The outer `run_guarded`'s resume point, if we're nested in one.
+    "push qword ptr [rip + {resume}]",
+    "mov [rip + {resume}], rsp",
+    // We were entered 8 bytes off of 16 byte stack alignment, and seven pushes
+    // line it back up, like calls need.
+    // Call `rsi(rdi)`
+    "call rsi",
+    "mov eax, 1",
+    "jmp 2f",
+    ".global __testing_abort",
+    "__testing_abort:",
+    "mov rsp, [rip + {resume}]",
+    "mov rax, rdi",
+    "2:",
+    "pop qword ptr [rip + {resume}]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    resume = sym RESUME_RSP,
+);
+
+extern "sysv64" {
+    /// Calls `call(data)`; returns 1 if it returned, or whatever it was aborted with.
+    fn __testing_run_guarded(data: *const (), call: extern "sysv64" fn(*const ())) -> u64;
+    /// Jump back into `__testing_run_guarded`, making it return `result`.
+    fn __testing_abort(result: u64) -> !;
+}
+
+/// Run `test`, returning `true` if it finished, or what it was cut short with
+/// by `abort_test`.
+pub fn run_guarded(test: &dyn Fn()) -> bool {
+    extern "sysv64" fn call(data: *const ()) {
+        let test = unsafe { *(data as *const &dyn Fn()) };
+        test();
+    }
+
+    let interrupts_enabled = interrupts::are_enabled();
+    let passed =
+        unsafe { __testing_run_guarded(&test as *const &dyn Fn() as *const (), call) } != 0;
+    // We may have been aborted from a panic inside an interrupt handler, or a
+    // `without_interrupts` block.
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    passed
+}
+
+/// Whether we're inside `run_guarded`.
+pub fn in_guarded_test() -> bool {
+    RESUME_RSP.load(Ordering::SeqCst) != 0
+}
+
+/// Abandon the running test & have `run_guarded` return `passed`. Returns
+/// right away if there's no test to abandon.
+pub fn abort_test(passed: bool) {
+    if in_guarded_test() {
+        unsafe { __testing_abort(passed as u64) }
+    }
+}
+
+/// Collects formatted text in a fixed-size buffer (there's no heap for a
+/// `String`), so tests can check what something writes.
+#[cfg(test)]
+pub struct FmtBuffer {
+    bytes: [u8; 4096],
+    len: usize,
+}
+
+#[cfg(test)]
+impl FmtBuffer {
+    pub const fn new() -> FmtBuffer {
+        FmtBuffer {
+            bytes: [0; 4096],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Default for FmtBuffer {
+    fn default() -> Self {
+        FmtBuffer::new()
+    }
+}
+
+/// Errors once the buffer's full.
+#[cfg(test)]
+impl core::fmt::Write for FmtBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.bytes
+            .get_mut(self.len..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_run_guarded_returns_or_aborts() {
+        // We're already running inside the runner's `run_guarded`.
+        let outer = RESUME_RSP.load(Ordering::SeqCst);
+        let reached = core::cell::Cell::new(0);
+        assert!(run_guarded(&|| reached.set(reached.get() + 1)));
+        assert!(!run_guarded(&|| {
+            abort_test(false);
+            panic!("abort_test returned");
+        }));
+        assert!(run_guarded(&|| {
+            abort_test(true);
+            panic!("abort_test returned");
+        }));
+        assert_eq!(reached.get(), 1);
+        assert_eq!(RESUME_RSP.load(Ordering::SeqCst), outer);
+    }
+}
@@ -0,0 +1,257 @@
+//! time.rs
+//! A monotonic nanosecond clock, plus sleeping on it.
+//!
+//! The clock reads the best counter we've set up: the HPET, else the TSC (once
+//! `apic::init` has measured its rate), else timer ticks. Calendar time comes
+//! from the CMOS real-time clock (see `rtc.rs`), read once & then kept going
+//! off of the monotonic clock.
+
+use crate::{apic, hpet, interrupts, rtc, timer};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Latest `nanos()` handed out, so time never goes backwards.
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+/// Which `Clock` `nanos()` last read, & what it adds to that clock's reading
+/// so that switching to a better one doesn't make time jump.
+static CLOCK: AtomicU8 = AtomicU8::new(Clock::Ticks as u8);
+static CLOCK_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Unix time in nanoseconds, minus `nanos()`; 0 until we've read the RTC.
+static WALL_CLOCK_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// How a timer that stands in for the PIT (the HPET's, or the local APIC's)
+/// should interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// `hz` times a second. `interrupts::ticks()` counts these, so anything
+    /// but `interrupts::TIMER_HZ` makes ticks run fast or slow.
+    Periodic { hz: u64 },
+    /// Once, after `nanos` nanoseconds.
+    OneShot { nanos: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Clock {
+    Ticks,
+    Tsc,
+    Hpet,
+}
+
+/// Nanoseconds since boot (roughly; we only start counting precisely once
+/// there's a better clock than the ticks).
+pub fn nanos() -> u64 {
+    // Reading the clock, switching clocks & applying the offset go together;
+    // a `nanos()` in an interrupt handler part way through would apply one
+    // clock's offset to the other's reading.
+    without_interrupts(|| {
+        let (clock, raw) = if hpet::is_present() {
+            (Clock::Hpet, hpet::nanos())
+        } else if apic::tsc_frequency() != 0 {
+            let tsc = apic::tsc() as u128 * NANOS_PER_SECOND as u128;
+            (Clock::Tsc, (tsc / apic::tsc_frequency() as u128) as u64)
+        } else {
+            let ticks = interrupts::ticks();
+            (
+                Clock::Ticks,
+                ticks * (NANOS_PER_SECOND / interrupts::TIMER_HZ),
+            )
+        };
+        if CLOCK.load(Ordering::Relaxed) != clock as u8 {
+            // Carry on from where the old clock left off.
+            let last = LAST_NANOS.load(Ordering::Relaxed);
+            CLOCK_OFFSET.store(last.wrapping_sub(raw), Ordering::Relaxed);
+            CLOCK.store(clock as u8, Ordering::Relaxed);
+        }
+        let now = raw.wrapping_add(CLOCK_OFFSET.load(Ordering::Relaxed));
+        LAST_NANOS.fetch_max(now, Ordering::Relaxed).max(now)
+    })
+}
+
+/// A calendar date & time of day, in whatever time zone the RTC keeps (UTC on
+/// QEMU, & usually on PCs that don't dual boot Windows).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn unix_timestamp(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days * SECONDS_PER_DAY
+            + self.hour as i64 * 60 * 60
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    pub fn from_unix_timestamp(timestamp: i64) -> DateTime {
+        let (year, month, day) = civil_from_days(timestamp.div_euclid(SECONDS_PER_DAY));
+        let second = timestamp.rem_euclid(SECONDS_PER_DAY);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (second / (60 * 60)) as u8,
+            minute: (second / 60 % 60) as u8,
+            second: (second % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar; see
+/// [Howard Hinnant's date algorithms](https://howardhinnant.github.io/date_algorithms.html).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March, so the leap day is at the end.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil`: (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * march_month + 2) / 5 + 1;
+    let month = if march_month < 10 {
+        march_month + 3
+    } else {
+        march_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Re-read the RTC & restart the wall clock from it.
+/// - Note: only good to the second, b/c that's all the RTC counts.
+pub fn sync_wall_clock() -> Result<(), rtc::RtcError> {
+    let unix = rtc::read()?.unix_timestamp().max(0) as u64 * NANOS_PER_SECOND;
+    // Never 0, so we don't read the RTC again in `unix_nanos`.
+    WALL_CLOCK_OFFSET.store(unix.saturating_sub(nanos()).max(1), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Nanoseconds since the Unix epoch. Reads the RTC the first time; without
+/// one, it's as if we booted at the epoch.
+pub fn unix_nanos() -> u64 {
+    if WALL_CLOCK_OFFSET.load(Ordering::Relaxed) == 0 && sync_wall_clock().is_err() {
+        // Don't try again every time.
+        WALL_CLOCK_OFFSET.store(1, Ordering::Relaxed);
+    }
+    WALL_CLOCK_OFFSET.load(Ordering::Relaxed) + nanos()
+}
+
+/// The current date & time; `now().unix_timestamp()` for a Unix timestamp.
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp((unix_nanos() / NANOS_PER_SECOND) as i64)
+}
+
+/// Idle until `nanos()` reaches `at`.
+pub fn sleep_until(at: u64) {
+    // A kernel timer that does nothing, just so `idle` wakes us by then. If
+    // they're all in use, we wake up on the ticks instead.
+    let _timer = timer::Timer::new(at, || {});
+    while nanos() < at {
+        crate::idle::idle();
+    }
+}
+
+/// Idle for `nanos` nanoseconds.
+pub fn sleep(nanos: u64) {
+    sleep_until(self::nanos() + nanos);
+}
+
+/// Put a timer's `start` & `stop` functions through their paces: periodic
+/// ticks at 10x `TIMER_HZ`, a one-shot, then the PIT taking back over.
+#[cfg(test)]
+pub fn check_timer_modes<E: fmt::Debug>(start: fn(TimerMode) -> Result<(), E>, stop: fn()) {
+    let wait_ticks = |count| {
+        let start = interrupts::ticks();
+        while interrupts::ticks() < start + count {
+            x86_64::instructions::hlt();
+        }
+    };
+    let started_at = nanos();
+    start(TimerMode::Periodic {
+        hz: 10 * interrupts::TIMER_HZ,
+    })
+    .unwrap();
+    wait_ticks(10);
+    // ~56ms, where the PIT would have taken ~550ms.
+    assert!(nanos() - started_at < 300_000_000);
+
+    start(TimerMode::OneShot { nanos: 1_000_000 }).unwrap();
+    wait_ticks(1);
+    stop();
+    wait_ticks(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_unix_timestamps() {
+        let dates = [
+            (0, (1970, 1, 1, 0, 0, 0)),
+            (951_782_400, (2000, 2, 29, 0, 0, 0)),
+            (1_700_000_000, (2023, 11, 14, 22, 13, 20)),
+            (4_107_542_399, (2100, 2, 28, 23, 59, 59)),
+        ];
+        for (timestamp, (year, month, day, hour, minute, second)) in dates {
+            let date = DateTime {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+            };
+            assert_eq!(DateTime::from_unix_timestamp(timestamp), date);
+            assert_eq!(date.unix_timestamp(), timestamp);
+        }
+    }
+
+    #[test_case]
+    fn test_now_follows_the_rtc() {
+        let (now, rtc) = (now(), rtc::read().unwrap());
+        assert!(now.year >= 2024);
+        assert!(now.unix_timestamp().abs_diff(rtc.unix_timestamp()) <= 2);
+    }
+
+    #[test_case]
+    fn test_sleep() {
+        let start = nanos();
+        sleep(100_000_000);
+        assert!(nanos() - start >= 100_000_000);
+    }
+}
@@ -0,0 +1,490 @@
+//! timer.rs
+//! Kernel timers: call a function at a deadline, once or every so often,
+//! without anyone spinning on `time::nanos()`. Plus `Sleep`, a future that's
+//! ready once its deadline passes, & `block_on` to wait for one.
+//!
+//! Timers are kept in a hierarchical timing wheel (like Linux's): `LEVELS`
+//! rings of 64 buckets, where level n's buckets are each 64^n ticks wide. A
+//! timer goes in the lowest level its deadline fits in, & the timer interrupt
+//! advances the wheel a tick at a time. Whenever a level's ring comes around,
+//! its next bucket is "cascaded" down into the levels below, so adding,
+//! cancelling & expiring a timer are all O(1).
+//!
+//! Callbacks don't run in the interrupt handler: expiring only marks a timer
+//! as due, & `run_pending` runs them later, from `idle::idle` (i.e. whenever
+//! the CPU would otherwise halt).
+//! - Note: there's no heap, so timers come out of a fixed pool of 64 & each
+//!   bucket is a bitmask of the pool slots in it.
+
+use crate::{idle, interrupts, time};
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Most timers that can exist at once.
+pub const MAX_TIMERS: usize = 64;
+const LEVELS: usize = 4;
+const BUCKET_BITS: u32 = 6;
+const BUCKETS: usize = 1 << BUCKET_BITS;
+/// Furthest ahead, in ticks, the wheel can hold a timer (~10 days). Later ones
+/// are parked in the top level & re-sorted each time it comes around.
+const HORIZON: u64 = 1 << (BUCKET_BITS * LEVELS as u32);
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// All `MAX_TIMERS` timers are in use.
+    PoolFull,
+}
+
+/// What to do when a timer goes off.
+#[derive(Clone)]
+enum Action {
+    Call(fn()),
+    Wake(Waker),
+}
+
+struct Slot {
+    /// `None` if the slot's free.
+    action: Option<Action>,
+    /// Tick it goes off at.
+    deadline: u64,
+    /// Ticks between goes; 0 for one-shot timers.
+    period: u64,
+    /// (level, bucket) while it's in the wheel.
+    position: Option<(usize, usize)>,
+}
+
+const FREE_SLOT: Slot = Slot {
+    action: None,
+    deadline: 0,
+    period: 0,
+    position: None,
+};
+
+struct Wheel {
+    /// Tick the wheel has been advanced to.
+    now: u64,
+    buckets: [[u64; BUCKETS]; LEVELS],
+    /// Slots in the wheel.
+    scheduled: u64,
+    /// Slots that have gone off, but whose action hasn't been run yet.
+    due: u64,
+    slots: [Slot; MAX_TIMERS],
+}
+
+impl Wheel {
+    const fn new() -> Wheel {
+        Wheel {
+            now: 0,
+            buckets: [[0; BUCKETS]; LEVELS],
+            scheduled: 0,
+            due: 0,
+            slots: [FREE_SLOT; MAX_TIMERS],
+        }
+    }
+
+    fn allocate(&mut self, action: Action) -> Option<usize> {
+        let slot = self.slots.iter().position(|slot| slot.action.is_none())?;
+        self.slots[slot].action = Some(action);
+        Some(slot)
+    }
+
+    fn free(&mut self, slot: usize) {
+        self.cancel(slot);
+        self.slots[slot] = FREE_SLOT;
+    }
+
+    fn schedule(&mut self, slot: usize, deadline: u64, period: u64) {
+        self.cancel(slot);
+        self.slots[slot].deadline = deadline;
+        self.slots[slot].period = period;
+        self.insert(slot);
+    }
+
+    fn cancel(&mut self, slot: usize) {
+        if let Some((level, bucket)) = self.slots[slot].position.take() {
+            self.buckets[level][bucket] &= !(1 << slot);
+        }
+        self.scheduled &= !(1 << slot);
+        self.due &= !(1 << slot);
+    }
+
+    fn is_pending(&self, slot: usize) -> bool {
+        (self.scheduled | self.due) & (1 << slot) != 0
+    }
+
+    /// Put `slot` in the bucket for its deadline, or mark it due if that's
+    /// passed (& then put a periodic timer in the bucket for its next go).
+    fn insert(&mut self, slot: usize) {
+        let mut deadline = self.slots[slot].deadline;
+        if deadline <= self.now {
+            self.due |= 1 << slot;
+            let period = self.slots[slot].period;
+            if period == 0 {
+                return;
+            }
+            // If we've missed some goes, skip them rather than firing for each.
+            deadline = (deadline + period).max(self.now + 1);
+            self.slots[slot].deadline = deadline;
+        }
+        let at = deadline.min(self.now + HORIZON - 1);
+        let delta = at - self.now;
+        // Level n holds deadlines 64^n to 64^(n+1) ticks away.
+        let level = ((u64::BITS - 1 - delta.leading_zeros()) / BUCKET_BITS) as usize;
+        let bucket = (at >> (BUCKET_BITS * level as u32)) as usize % BUCKETS;
+        self.buckets[level][bucket] |= 1 << slot;
+        self.scheduled |= 1 << slot;
+        self.slots[slot].position = Some((level, bucket));
+    }
+
+    /// Empty a bucket, re-inserting what was in it relative to `now`.
+    fn cascade(&mut self, level: usize, bucket: usize) {
+        let mut slots = core::mem::take(&mut self.buckets[level][bucket]);
+        self.scheduled &= !slots;
+        while slots != 0 {
+            let slot = slots.trailing_zeros() as usize;
+            slots &= slots - 1;
+            self.slots[slot].position = None;
+            self.insert(slot);
+        }
+    }
+
+    fn advance_to(&mut self, ticks: u64) {
+        while self.now < ticks {
+            if self.scheduled == 0 {
+                // Nothing to expire or cascade on the way.
+                self.now = ticks;
+                return;
+            }
+            self.now += 1;
+            for level in 1..LEVELS {
+                let shift = BUCKET_BITS * level as u32;
+                if self.now & ((1 << shift) - 1) != 0 {
+                    break;
+                }
+                self.cascade(level, (self.now >> shift) as usize % BUCKETS);
+            }
+            self.cascade(0, self.now as usize % BUCKETS);
+        }
+    }
+
+    /// Tick the next timer goes off at.
+    fn next_expiry(&self) -> Option<u64> {
+        if self.due != 0 {
+            return Some(self.now);
+        }
+        (0..MAX_TIMERS)
+            .filter(|&slot| self.scheduled & (1 << slot) != 0)
+            .map(|slot| self.slots[slot].deadline)
+            .min()
+    }
+
+    /// Take the action of a timer that's gone off.
+    fn take_due(&mut self) -> Option<Action> {
+        if self.due == 0 {
+            return None;
+        }
+        let slot = self.due.trailing_zeros() as usize;
+        self.due &= !(1 << slot);
+        self.slots[slot].action.clone()
+    }
+}
+
+/// Lock the wheel, brought up to date with `interrupts::ticks()`.
+fn with_wheel<T>(f: impl FnOnce(&mut Wheel) -> T) -> T {
+    // The timer interrupt takes this lock too, so hold it with interrupts off.
+    without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        wheel.advance_to(interrupts::ticks());
+        f(&mut wheel)
+    })
+}
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    let ticks = nanos as u128 * interrupts::TIMER_HZ as u128;
+    ticks.div_ceil(time::NANOS_PER_SECOND as u128) as u64
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    // Rounded up, so that waking up this long from now is at or past the tick.
+    let nanos = ticks as u128 * time::NANOS_PER_SECOND as u128;
+    nanos.div_ceil(interrupts::TIMER_HZ as u128) as u64
+}
+
+/// The tick a `time::nanos()` deadline falls in (or after).
+fn deadline_to_ticks(deadline: u64) -> u64 {
+    interrupts::ticks() + nanos_to_ticks(deadline.saturating_sub(time::nanos()))
+}
+
+/// Called from the timer interrupt, & by `idle` after skipping ticks.
+pub fn advance() {
+    WHEEL.lock().advance_to(interrupts::ticks());
+}
+
+/// Run the callbacks of timers that have gone off (& wake their futures).
+/// Returns whether there were any.
+pub fn run_pending() -> bool {
+    let mut ran = false;
+    while let Some(action) = with_wheel(Wheel::take_due) {
+        match action {
+            Action::Call(callback) => callback(),
+            Action::Wake(waker) => waker.wake(),
+        }
+        ran = true;
+    }
+    ran
+}
+
+/// Whether `run_pending` has anything to do. `false` if the wheel's busy.
+/// - Note: for `idle`, with interrupts off.
+pub fn has_pending() -> bool {
+    WHEEL.try_lock().is_some_and(|wheel| wheel.due != 0)
+}
+
+/// When the next timer goes off, in `time::nanos()`. `None` if there isn't one
+/// (or the wheel's busy).
+/// - Note: for `idle`, with interrupts off.
+pub fn next_expiry() -> Option<u64> {
+    let ticks = WHEEL.try_lock()?.next_expiry()?;
+    Some(time::nanos() + ticks_to_nanos(ticks.saturating_sub(interrupts::ticks())))
+}
+
+/// A kernel timer, good to a timer tick. Dropping it cancels it.
+#[derive(Debug)]
+pub struct Timer {
+    slot: usize,
+}
+
+impl Timer {
+    /// Call `callback` once `time::nanos()` reaches `deadline`.
+    pub fn new(deadline: u64, callback: fn()) -> Result<Timer, TimerError> {
+        Timer::with_action(Action::Call(callback), deadline, 0)
+    }
+
+    /// Call `callback` at `deadline`, & then every `period` nanoseconds.
+    pub fn periodic(deadline: u64, period: u64, callback: fn()) -> Result<Timer, TimerError> {
+        let period = nanos_to_ticks(period).max(1);
+        Timer::with_action(Action::Call(callback), deadline, period)
+    }
+
+    fn with_action(action: Action, deadline: u64, period: u64) -> Result<Timer, TimerError> {
+        let deadline = deadline_to_ticks(deadline);
+        with_wheel(|wheel| {
+            let slot = wheel.allocate(action).ok_or(TimerError::PoolFull)?;
+            wheel.schedule(slot, deadline, period);
+            Ok(Timer { slot })
+        })
+    }
+
+    /// Stop it going off (until it's rescheduled).
+    pub fn cancel(&self) {
+        with_wheel(|wheel| wheel.cancel(self.slot));
+    }
+
+    /// Move it to go off at `deadline` instead, whether it's pending, cancelled
+    /// or already gone off. Periodic timers keep their period.
+    pub fn reschedule(&self, deadline: u64) {
+        let deadline = deadline_to_ticks(deadline);
+        with_wheel(|wheel| {
+            let period = wheel.slots[self.slot].period;
+            wheel.schedule(self.slot, deadline, period);
+        });
+    }
+
+    /// Whether it's yet to go off, or has but its callback hasn't run yet.
+    pub fn is_pending(&self) -> bool {
+        with_wheel(|wheel| wheel.is_pending(self.slot))
+    }
+
+    /// For `Sleep`: wake `waker` instead, & at `deadline` if it's gone off.
+    fn wake_at(&self, waker: &Waker, deadline: u64) {
+        let ticks = deadline_to_ticks(deadline);
+        with_wheel(|wheel| {
+            let slot = &mut wheel.slots[self.slot];
+            if !matches!(&slot.action, Some(Action::Wake(old)) if old.will_wake(waker)) {
+                slot.action = Some(Action::Wake(waker.clone()));
+            }
+            if !wheel.is_pending(self.slot) {
+                wheel.schedule(self.slot, ticks, 0);
+            }
+        });
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        with_wheel(|wheel| wheel.free(self.slot));
+    }
+}
+
+/// Future that's ready once `time::nanos()` reaches its deadline.
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: u64,
+    timer: Option<Timer>,
+}
+
+/// A future that's ready `nanos` nanoseconds from now.
+pub fn sleep(nanos: u64) -> Sleep {
+    sleep_until(time::nanos() + nanos)
+}
+
+/// A future that's ready once `time::nanos()` reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::nanos() >= self.deadline {
+            self.timer = None;
+            return Poll::Ready(());
+        }
+        match &self.timer {
+            Some(timer) => timer.wake_at(cx.waker(), self.deadline),
+            None => match Timer::with_action(Action::Wake(cx.waker().clone()), self.deadline, 0) {
+                Ok(timer) => self.timer = Some(timer),
+                // Out of timers; get polled again until one frees up.
+                Err(TimerError::PoolFull) => cx.waker().wake_by_ref(),
+            },
+        }
+        Poll::Pending
+    }
+}
+
+/// Set by `block_on`'s waker.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+static BLOCK_ON_WAKER: RawWakerVTable = RawWakerVTable::new(
+    |_| RawWaker::new(ptr::null(), &BLOCK_ON_WAKER),
+    |_| WOKEN.store(true, Ordering::SeqCst),
+    |_| WOKEN.store(true, Ordering::SeqCst),
+    |_| {},
+);
+
+/// Poll `future` until it's done, idling in between. We don't have an executor
+/// yet, so this is how to wait on a `Sleep`.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    // No data to free, so the vtable's functions are trivially sound.
+    let waker = unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &BLOCK_ON_WAKER)) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        WOKEN.store(false, Ordering::SeqCst);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        while !WOKEN.load(Ordering::SeqCst) {
+            idle::idle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicU32;
+
+    #[test_case]
+    fn test_wheel_expires_on_time() {
+        let mut wheel = Wheel::new();
+        wheel.advance_to(1000);
+        // One for each level & one already due.
+        let deadlines = [1010, 1000 + 64, 1000 + 5000, 1000 + 300_000, 900];
+        for &deadline in &deadlines {
+            let slot = wheel.allocate(Action::Call(|| {})).unwrap();
+            wheel.schedule(slot, deadline, 0);
+        }
+        assert_eq!(wheel.due, 1 << 4);
+        wheel.due = 0;
+        let levels = wheel.slots[..4].iter().map(|slot| slot.position.unwrap().0);
+        assert!(levels.eq(0..LEVELS));
+
+        for (slot, &deadline) in deadlines.iter().enumerate().take(4) {
+            assert_eq!(wheel.next_expiry(), Some(deadline));
+            wheel.advance_to(deadline - 1);
+            assert_eq!(wheel.due, 0, "timer {} went off early", slot);
+            wheel.advance_to(deadline);
+            assert_eq!(wheel.due, 1 << slot, "timer {} didn't go off", slot);
+            wheel.due = 0;
+        }
+        assert_eq!(wheel.next_expiry(), None);
+
+        // Too far out to fit, so it waits at the far end of the top level.
+        let slot = wheel.allocate(Action::Call(|| {})).unwrap();
+        wheel.schedule(slot, wheel.now + HORIZON + 7, 0);
+        assert_eq!(wheel.slots[slot].position.unwrap().0, LEVELS - 1);
+        assert_eq!(wheel.next_expiry(), Some(wheel.now + HORIZON + 7));
+    }
+
+    #[test_case]
+    fn test_wheel_periodic_and_cancel() {
+        let mut wheel = Wheel::new();
+        let periodic = wheel.allocate(Action::Call(|| {})).unwrap();
+        let cancelled = wheel.allocate(Action::Call(|| {})).unwrap();
+        wheel.schedule(periodic, 10, 100);
+        wheel.schedule(cancelled, 50, 0);
+        wheel.cancel(cancelled);
+        wheel.advance_to(10);
+        assert_eq!(wheel.due, 1 << periodic);
+        assert!(wheel.take_due().is_some());
+        // Missed a couple; those get skipped.
+        wheel.advance_to(400);
+        assert_eq!(wheel.due, 1 << periodic);
+        assert_eq!(wheel.slots[periodic].deadline, 410);
+        wheel.free(periodic);
+        assert!(!wheel.is_pending(periodic) && !wheel.is_pending(cancelled));
+    }
+
+    static CALLS: AtomicU32 = AtomicU32::new(0);
+
+    fn count_call() {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn test_timer_callbacks() {
+        CALLS.store(0, Ordering::SeqCst);
+        let start = time::nanos();
+        let once = Timer::new(start + 100_000_000, count_call).unwrap();
+        let cancelled = Timer::new(start + 100_000_000, count_call).unwrap();
+        cancelled.cancel();
+        time::sleep(300_000_000);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert!(!once.is_pending());
+
+        once.reschedule(time::nanos());
+        let periodic = Timer::periodic(time::nanos(), 100_000_000, count_call).unwrap();
+        time::sleep(300_000_000);
+        drop(periodic);
+        assert!(CALLS.load(Ordering::SeqCst) >= 4);
+    }
+
+    #[test_case]
+    fn test_async_sleep() {
+        let start = time::nanos();
+        block_on(sleep(100_000_000));
+        assert!(time::nanos() - start >= 100_000_000);
+        // Its timer went back to the pool.
+        assert_eq!(
+            WHEEL
+                .lock()
+                .slots
+                .iter()
+                .filter(|slot| slot.action.is_some())
+                .count(),
+            0
+        );
+    }
+}
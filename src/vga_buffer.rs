@@ -18,6 +18,7 @@ lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Black, Color::White),
+        status_line: false,
         buffer: unsafe { &mut *(VGA_BUFFER_ADDRESS as *mut Buffer) },
     });
 }
@@ -67,9 +68,11 @@ pub struct Buffer {
 /// The public interface with which to interact with the our VGA driver.
 /// - Always writes to last line and shifts up if fill or when encountering '\n'.
 /// - Reference to VGA buffer needs to live for the entire program life: `'static`.
+/// - Once `set_status_line` is used, the top row is kept for it & doesn't scroll.
 pub struct Writer {
     pub column_position: usize,
     pub color_code: ColorCode,
+    pub status_line: bool,
     pub buffer: &'static mut Buffer,
 }
 
@@ -115,9 +118,10 @@ impl Writer {
         }
     }
 
-    /// Shift all rows up 1  
+    /// Shift all rows up 1 (bar the status line)
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
+        let top = self.status_line as usize;
+        for row in top + 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
                 self.buffer.chars[row - 1][col].write(character);
@@ -145,6 +149,52 @@ impl fmt::Write for Writer {
     }
 }
 
+/// Writes into the status line, in inverted colors, dropping whatever doesn't fit.
+struct StatusLine<'a> {
+    buffer: &'a mut Buffer,
+    column: usize,
+}
+
+impl fmt::Write for StatusLine<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.column == BUFFER_WIDTH {
+                break;
+            }
+            let byte = match byte {
+                0x20..=0x7e => byte,
+                _ => 0xfe,
+            };
+            self.buffer.chars[0][self.column].write(ScreenChar {
+                ascii_character: byte,
+                color_code: ColorCode::new(Color::White, Color::Black),
+            });
+            self.column += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Show `args` on the status line: the top row, which stops scrolling with
+/// the rest of the screen from the first call on.
+pub fn set_status_line(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.status_line = true;
+        let mut line = StatusLine {
+            buffer: &mut *writer.buffer,
+            column: 0,
+        };
+        line.write_fmt(args).unwrap();
+        while line.column < BUFFER_WIDTH {
+            line.write_str(" ").unwrap();
+        }
+    });
+}
+
 /*
 Create our own declarative print & println macros which use the above `fmt::Write`
 impl instead of the Rust IO module's `_print` function. Only real diff is ours
@@ -171,6 +221,155 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/*
+Custom fonts: In text mode the VGA card draws each character cell using a glyph
+bitmap stored in plane 2 of its video memory. Normally plane 2 is hidden from us
+(the 0xb8000 window only exposes planes 0 & 1 in "odd/even" mode), so to swap
+glyphs we temporarily reprogram the sequencer and graphics controller to expose
+plane 2 at 0xa0000, copy the bitmaps in, and then put everything back.
+See https://wiki.osdev.org/VGA_Fonts
+*/
+
+/// Bytes in an 8x16 font: 256 glyphs, 16 rows of 8 pixels (1 byte) each.
+pub const FONT_SIZE: usize = GLYPH_COUNT * GLYPH_HEIGHT;
+
+const GLYPH_COUNT: usize = 256;
+const GLYPH_HEIGHT: usize = 16;
+// Plane 2 reserves 32 bytes for every glyph, even though we only use 16 rows.
+const GLYPH_STRIDE: usize = 32;
+const VGA_PLANE_ADDRESS: u64 = 0xa0000;
+
+const SEQUENCER_INDEX_PORT: u16 = 0x3c4;
+const GRAPHICS_CONTROLLER_INDEX_PORT: u16 = 0x3ce;
+
+// Register indices within the sequencer & graphics controller.
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+const GC_READ_MAP_SELECT: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+
+/// Copy of whatever font the BIOS loaded, taken the first time we overwrite it.
+static BIOS_FONT: Mutex<Option<[u8; FONT_SIZE]>> = Mutex::new(None);
+
+/// Holds the VGA registers we clobber while plane 2 is exposed; the original
+/// text mode configuration is restored when this is dropped.
+struct FontPlane {
+    map_mask: u8,
+    memory_mode: u8,
+    read_map_select: u8,
+    mode: u8,
+    misc: u8,
+}
+
+impl FontPlane {
+    /// Map plane 2 (and only plane 2) linearly at 0xa0000 for reads & writes.
+    fn expose() -> FontPlane {
+        let saved = FontPlane {
+            map_mask: read_indexed(SEQUENCER_INDEX_PORT, SEQ_MAP_MASK),
+            memory_mode: read_indexed(SEQUENCER_INDEX_PORT, SEQ_MEMORY_MODE),
+            read_map_select: read_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_READ_MAP_SELECT),
+            mode: read_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_MODE),
+            misc: read_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_MISC),
+        };
+
+        // Writes only go to plane 2; sequential addressing, odd/even disabled.
+        write_indexed(SEQUENCER_INDEX_PORT, SEQ_MAP_MASK, 0x04);
+        write_indexed(SEQUENCER_INDEX_PORT, SEQ_MEMORY_MODE, 0x07);
+        // Reads come from plane 2; odd/even off; memory mapped at 0xa0000 (64K).
+        write_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_READ_MAP_SELECT, 0x02);
+        write_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_MODE, 0x00);
+        write_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_MISC, 0x04);
+
+        saved
+    }
+
+    fn glyph_ptr(glyph: usize) -> *mut u8 {
+        let base = crate::memory::phys_to_virt(x86_64::PhysAddr::new(VGA_PLANE_ADDRESS));
+        (base + glyph * GLYPH_STRIDE).as_mut_ptr()
+    }
+
+    fn read(&self, font: &mut [u8; FONT_SIZE]) {
+        for (glyph, rows) in font.chunks_exact_mut(GLYPH_HEIGHT).enumerate() {
+            let src = Self::glyph_ptr(glyph);
+            for (row, byte) in rows.iter_mut().enumerate() {
+                *byte = unsafe { core::ptr::read_volatile(src.add(row)) };
+            }
+        }
+    }
+
+    fn write(&mut self, font: &[u8; FONT_SIZE]) {
+        for (glyph, rows) in font.chunks_exact(GLYPH_HEIGHT).enumerate() {
+            let dst = Self::glyph_ptr(glyph);
+            for (row, byte) in rows.iter().enumerate() {
+                unsafe { core::ptr::write_volatile(dst.add(row), *byte) };
+            }
+        }
+    }
+}
+
+impl Drop for FontPlane {
+    fn drop(&mut self) {
+        write_indexed(SEQUENCER_INDEX_PORT, SEQ_MAP_MASK, self.map_mask);
+        write_indexed(SEQUENCER_INDEX_PORT, SEQ_MEMORY_MODE, self.memory_mode);
+        write_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_READ_MAP_SELECT, self.read_map_select);
+        write_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_MODE, self.mode);
+        write_indexed(GRAPHICS_CONTROLLER_INDEX_PORT, GC_MISC, self.misc);
+    }
+}
+
+/// The sequencer & graphics controller use an index/data port pair: write the
+/// register index to the index port, then access the value on the next port.
+fn read_indexed(index_port: u16, index: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::<u8>::new(index_port).write(index);
+        Port::<u8>::new(index_port + 1).read()
+    }
+}
+
+fn write_indexed(index_port: u16, index: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::<u8>::new(index_port).write(index);
+        Port::<u8>::new(index_port + 1).write(value);
+    }
+}
+
+/// Replace the glyphs used for all 256 code points with an 8x16 `font`;
+/// glyph `n` is bytes `n * 16..n * 16 + 16`, one byte per row, MSB leftmost.
+/// The BIOS font is saved the first time this is called so that it can be
+/// put back with `restore_default_font`.
+/// - Note: requires `memory::init` to have been called.
+pub fn load_font(font: &[u8; FONT_SIZE]) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut bios_font = BIOS_FONT.lock();
+        let mut plane = FontPlane::expose();
+        if bios_font.is_none() {
+            let mut original = [0; FONT_SIZE];
+            plane.read(&mut original);
+            *bios_font = Some(original);
+        }
+        plane.write(font);
+    });
+}
+
+/// Put back the font the BIOS set up before our first `load_font` call.
+/// Does nothing if we never replaced it.
+pub fn restore_default_font() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(original) = BIOS_FONT.lock().as_ref() {
+            FontPlane::expose().write(original);
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -188,6 +387,13 @@ mod tests {
         }
     }
 
+    #[crate::kernel_bench]
+    fn bench_new_line(b: &mut crate::bench::Bencher) {
+        use x86_64::instructions::interrupts;
+        // Scrolls the whole screen up a row.
+        b.iter(|| interrupts::without_interrupts(|| WRITER.lock().new_line()));
+    }
+
     #[test_case]
     fn test_println_output() {
         use x86_64::instructions::interrupts;
@@ -227,4 +433,44 @@ mod tests {
             }
         });
     }
+
+    #[test_case]
+    fn test_status_line_stays_put() {
+        use x86_64::instructions::interrupts;
+
+        set_status_line(format_args!("status {}", 1));
+        for _ in 0..BUFFER_HEIGHT {
+            println!("scrolling");
+        }
+        interrupts::without_interrupts(|| {
+            let writer = WRITER.lock();
+            let row = &writer.buffer.chars[0];
+            for (i, c) in "status 1 ".chars().enumerate() {
+                assert_eq!(char::from(row[i].read().ascii_character), c);
+            }
+        });
+    }
+
+    #[test_case]
+    fn test_load_and_restore_font() {
+        use x86_64::instructions::interrupts;
+
+        let mut font = [0; FONT_SIZE];
+        for (i, byte) in font.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut original = [0; FONT_SIZE];
+        let mut loaded = [0; FONT_SIZE];
+        let mut restored = [0; FONT_SIZE];
+        interrupts::without_interrupts(|| FontPlane::expose().read(&mut original));
+
+        load_font(&font);
+        interrupts::without_interrupts(|| FontPlane::expose().read(&mut loaded));
+        restore_default_font();
+        interrupts::without_interrupts(|| FontPlane::expose().read(&mut restored));
+
+        assert!(loaded == font);
+        assert!(restored == original);
+    }
 }
@@ -4,6 +4,10 @@
 //! disable the test harness and avoid the need for a test runner.
 //!
 //! See `Cargo.toml` for test harness disabling config...
+//!
+//! - Note: tests that share our test runner can use `#[kernel_test(should_panic)]`
+//!   instead; a separate binary like this is only needed when the panic leaves
+//!   the kernel unusable (e.g. a double fault).
 
 #![no_std]
 #![no_main]
@@ -0,0 +1,46 @@
+#!/bin/bash
+# Run the kernel's tests with coverage instrumentation, then turn the profiles
+# they dump over serial (see src/coverage.rs) into an lcov report at
+# target/coverage/lcov.info. Extra arguments go to `cargo test`.
+#
+# Needs clang (minicov compiles LLVM's profiling runtime for our target) & the
+# `llvm-tools-preview` rustup component (for llvm-profdata & llvm-cov).
+set -eo pipefail
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+out="$root/target/coverage"
+tools="$(rustc --print sysroot)/lib/rustlib/$(rustc -vV | sed -n 's/^host: //p')/bin"
+rm -rf "$out"
+mkdir -p "$out"
+cd "$root"
+
+# No profiler runtime b/c minicov is ours. Only our target gets these flags, so
+# the proc macros (built for the host) don't.
+export RUSTFLAGS="-C instrument-coverage -Z no-profiler-runtime"
+
+status=0
+cargo test --features coverage "$@" 2>&1 | tee "$out/test_output.txt" || status=$?
+
+# Each test binary that uses our test runner dumps its own profile.
+awk -v out="$out" '
+	/^--- coverage profraw ---/ { count++; file = out "/" count ".hex"; next }
+	/^--- end coverage ---/ { file = ""; next }
+	file != "" { gsub(/\r/, ""); print > file }
+' "$out/test_output.txt"
+for hex in "$out"/*.hex; do
+	[ -e "$hex" ] || { echo "coverage: no profiles in the test output" >&2; exit 1; }
+	xxd -r -p "$hex" > "${hex%.hex}.profraw"
+done
+"$tools/llvm-profdata" merge -sparse "$out"/*.profraw -o "$out/kernel.profdata"
+
+# llvm-cov needs the instrumented binaries the profiles came from.
+objects=()
+for exe in $(cargo test --features coverage --no-run --message-format=json "$@" 2>/dev/null \
+	| grep -o '"executable":"[^"]*"' | cut -d '"' -f 4); do
+	objects+=(-object "$exe")
+done
+"$tools/llvm-cov" export -format=lcov -instr-profile="$out/kernel.profdata" \
+	-ignore-filename-regex='/.cargo/registry|/rustc/|/library/' \
+	"${objects[@]}" > "$out/lcov.info"
+echo "coverage: wrote $out/lcov.info" >&2
+exit $status
@@ -0,0 +1,172 @@
+//! ksyms.rs
+//! Host tool that fills in the kernel's `.ksyms` section (see `src/ksyms.rs`)
+//! with the function symbols from the linked kernel ELF's own `.symtab`.
+//! Run by `tools/runner.sh` before every boot; it's plain std Rust with no
+//! dependencies so it can be built with `rustc` alone, outside our no_std
+//! cargo config.
+//!
+//! > rustc --edition 2021 -O tools/ksyms.rs -o target/ksyms && target/ksyms <kernel ELF>
+
+use std::{env, fs, process};
+
+/// - Note: must match `KSYMS_SIZE` in `src/ksyms.rs`.
+const KSYMS_SIZE: usize = 512 * 1024;
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+struct Section {
+    name: u32,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16, String> {
+    let field = bytes.get(at..at + 2).ok_or("truncated ELF")?;
+    Ok(u16::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32, String> {
+    let field = bytes.get(at..at + 4).ok_or("truncated ELF")?;
+    Ok(u32::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Result<u64, String> {
+    let field = bytes.get(at..at + 8).ok_or("truncated ELF")?;
+    Ok(u64::from_le_bytes(field.try_into().unwrap()))
+}
+
+/// The NUL terminated string at `at` in a string table section.
+fn str_at<'a>(elf: &'a [u8], table: &Section, at: usize) -> Result<&'a str, String> {
+    let table = elf
+        .get(table.offset..table.offset + table.size)
+        .ok_or("string table out of bounds")?;
+    let name = table.get(at..).ok_or("string out of bounds")?;
+    let len = name
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or("unterminated string")?;
+    std::str::from_utf8(&name[..len]).map_err(|e| e.to_string())
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    // 64 bit, little endian
+    if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+        return Err("not a 64 bit little endian ELF".into());
+    }
+    let table = u64_at(elf, 0x28)? as usize;
+    let count = u16_at(elf, 0x3c)? as usize;
+    (0..count)
+        .map(|i| {
+            let header = table + i * SECTION_HEADER_SIZE;
+            Ok(Section {
+                name: u32_at(elf, header)?,
+                kind: u32_at(elf, header + 4)?,
+                offset: u64_at(elf, header + 24)? as usize,
+                size: u64_at(elf, header + 32)? as usize,
+                link: u32_at(elf, header + 40)? as usize,
+            })
+        })
+        .collect()
+}
+
+/// Function symbols as `(address, size, name)`, sorted by address.
+fn functions<'a>(elf: &'a [u8], sections: &[Section]) -> Result<Vec<(u64, u32, &'a str)>, String> {
+    let symtab = sections
+        .iter()
+        .find(|s| s.kind == SHT_SYMTAB)
+        .ok_or("no .symtab (was the kernel stripped?)")?;
+    let strtab = sections.get(symtab.link).ok_or("bad .symtab link")?;
+    let mut functions = Vec::new();
+    for at in (symtab.offset..symtab.offset + symtab.size).step_by(SYMBOL_SIZE) {
+        let info = *elf.get(at + 4).ok_or("truncated .symtab")?;
+        let address = u64_at(elf, at + 8)?;
+        if info & 0xf != STT_FUNC || address == 0 {
+            continue;
+        }
+        let name = str_at(elf, strtab, u32_at(elf, at)? as usize)?;
+        let size = u64_at(elf, at + 16)?.min(u32::MAX as u64) as u32;
+        functions.push((address, size, name));
+    }
+    functions.sort();
+    // Aliases share an address; the first name is as good as any.
+    functions.dedup_by_key(|&mut (address, _, _)| address);
+    Ok(functions)
+}
+
+/// Encode the table in the layout `src/ksyms.rs` expects.
+fn encode(functions: &[(u64, u32, &str)]) -> Vec<u8> {
+    let names_offset = HEADER_SIZE + functions.len() * ENTRY_SIZE;
+    let mut table = Vec::with_capacity(KSYMS_SIZE);
+    table.extend_from_slice(b"KSYM");
+    table.extend_from_slice(&(functions.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(names_offset as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+
+    let mut names = Vec::new();
+    for &(address, size, name) in functions {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    table.extend_from_slice(&names);
+    table
+}
+
+fn patch(path: &str) -> Result<usize, String> {
+    let mut elf = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let sections = sections(&elf)?;
+    let shstrtab = sections
+        .get(u16_at(&elf, 0x3e)? as usize)
+        .ok_or("bad section name table index")?;
+    let mut ksyms = None;
+    for section in &sections {
+        if str_at(&elf, shstrtab, section.name as usize)? == ".ksyms" {
+            ksyms = Some(section);
+        }
+    }
+    let ksyms = ksyms.ok_or("no .ksyms section (is the ksyms module linked in?)")?;
+    if ksyms.kind != SHT_PROGBITS || ksyms.size != KSYMS_SIZE {
+        return Err(".ksyms section doesn't look like the one in src/ksyms.rs".into());
+    }
+
+    let functions = functions(&elf, &sections)?;
+    let (count, table) = (functions.len(), encode(&functions));
+    if table.len() > KSYMS_SIZE {
+        return Err(format!(
+            "{} bytes of symbols don't fit in .ksyms, bump KSYMS_SIZE (here & in src/ksyms.rs)",
+            table.len()
+        ));
+    }
+    let section = &mut elf[ksyms.offset..ksyms.offset + KSYMS_SIZE];
+    section.fill(0);
+    section[..table.len()].copy_from_slice(&table);
+    fs::write(path, elf).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(count)
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: ksyms <kernel ELF>");
+            process::exit(2);
+        }
+    };
+    match patch(&path) {
+        Ok(count) => eprintln!("ksyms: embedded {} symbols into {}", count, path),
+        Err(e) => {
+            eprintln!("ksyms: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+#!/bin/sh
+# Cargo runner (see `.cargo/config.toml`): embed the kernel's symbol table into
+# the freshly linked ELF so backtraces can print function names, then hand over
+# to `bootimage runner` as before. Arguments are the kernel ELF & any extra args,
+# which go to QEMU (or, for tests, are the test name filters).
+set -e
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+tool="$root/target/ksyms"
+
+# Build the (host) tool with plain rustc, b/c cargo would pick up our no_std
+# target & build-std settings.
+if [ ! -x "$tool" ] || [ "$root/tools/ksyms.rs" -nt "$tool" ]; then
+	mkdir -p "$root/target"
+	rustc --edition 2021 -O "$root/tools/ksyms.rs" -o "$tool"
+fi
+
+kernel="$1"
+shift
+
+case "$kernel" in
+	*/deps/*)
+		# A test binary. Bare words are test name filters (`cargo test -- <filter>`),
+		# which reach the kernel's test runner as a QEMU fw_cfg file (see src/fw_cfg.rs).
+		# `cargo bench` adds `--bench`, which switches the runner to measuring benchmarks.
+		filters=""
+		bench=""
+		for arg in "$@"; do
+			case "$arg" in
+				--bench) bench=1 ;;
+				-*) echo "runner: ignoring unsupported test flag $arg" >&2 ;;
+				*) filters="${filters:+$filters }$arg" ;;
+			esac
+		done
+		set --
+		if [ -n "$filters" ]; then
+			# QEMU option values escape commas by doubling them.
+			filters="$(printf '%s' "$filters" | sed 's/,/,,/g')"
+			set -- -fw_cfg "name=opt/thompson_rust_os/test-filter,string=$filters"
+		fi
+		if [ -n "$bench" ]; then
+			set -- "$@" -fw_cfg "name=opt/thompson_rust_os/bench,string=1"
+		fi
+		# Randomized-input tests (see src/fuzz.rs): replay one seed, or try more inputs.
+		if [ -n "$FUZZ_SEED" ]; then
+			set -- "$@" -fw_cfg "name=opt/thompson_rust_os/fuzz-seed,string=$FUZZ_SEED"
+		fi
+		if [ -n "$FUZZ_ITERATIONS" ]; then
+			set -- "$@" -fw_cfg "name=opt/thompson_rust_os/fuzz-iterations,string=$FUZZ_ITERATIONS"
+		fi
+		;;
+esac
+
+"$tool" "$kernel"
+exec bootimage runner "$kernel" "$@"